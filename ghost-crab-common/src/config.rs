@@ -1,7 +1,7 @@
 use dotenvy::dotenv;
 use serde::Deserialize;
 use serde_json::Error as SerdeError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{collections::HashMap, io::Error as IoError};
 use std::{env, fs};
 
@@ -10,14 +10,49 @@ use std::{env, fs};
 pub enum ExecutionMode {
     Parallel,
     Serial,
+    /// Handles the logs with the same key, chosen by the handler, in order,
+    /// and the logs with different keys concurrently.
+    Keyed,
 }
 
+/// Limits on the handlers run concurrently by a source in `Parallel` and `Keyed`
+/// modes.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyConfig {
+    /// Maximum number of handlers running at the same time, unlimited by default.
+    pub max_concurrency: Option<usize>,
+    /// Number of ranges the cursor can advance while the handlers of a range
+    /// are running, one by default. The writes of a range are committed once
+    /// its handlers are done. A block handler has a range per block, so it
+    /// handles at most `max_pending_ranges + 1` blocks at the same time, and
+    /// defaults to [`DEFAULT_BLOCK_PENDING_RANGES`].
+    pub max_pending_ranges: Option<usize>,
+}
+
+/// Default `max_pending_ranges` of the block handlers, whose ranges are single
+/// blocks, so they handle up to 33 blocks at the same time in `Parallel` mode.
+pub const DEFAULT_BLOCK_PENDING_RANGES: usize = 32;
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Template {
     pub abi: String,
     pub network: String,
     pub execution_mode: Option<ExecutionMode>,
+    #[serde(flatten)]
+    pub concurrency: ConcurrencyConfig,
+}
+
+impl Template {
+    pub fn new(abi: impl Into<String>, network: impl Into<String>) -> Self {
+        Template {
+            abi: abi.into(),
+            network: network.into(),
+            execution_mode: None,
+            concurrency: ConcurrencyConfig::default(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -28,6 +63,26 @@ pub struct DataSource {
     pub start_block: u64,
     pub network: String,
     pub execution_mode: Option<ExecutionMode>,
+    #[serde(flatten)]
+    pub concurrency: ConcurrencyConfig,
+}
+
+impl DataSource {
+    pub fn new(
+        abi: impl Into<String>,
+        address: impl Into<String>,
+        start_block: u64,
+        network: impl Into<String>,
+    ) -> Self {
+        DataSource {
+            abi: abi.into(),
+            address: address.into(),
+            start_block,
+            network: network.into(),
+            execution_mode: None,
+            concurrency: ConcurrencyConfig::default(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -36,23 +91,212 @@ pub struct BlockHandlerConfig {
     pub start_block: u64,
     pub network: String,
     pub execution_mode: Option<ExecutionMode>,
+    #[serde(flatten)]
+    pub concurrency: ConcurrencyConfig,
     pub step: u64,
 }
 
+impl BlockHandlerConfig {
+    pub fn new(start_block: u64, network: impl Into<String>, step: u64) -> Self {
+        BlockHandlerConfig {
+            start_block,
+            network: network.into(),
+            execution_mode: None,
+            concurrency: ConcurrencyConfig::default(),
+            step,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionDirection {
+    #[default]
+    To,
+    From,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionHandlerConfig {
+    pub abi: Option<String>,
+    pub address: String,
+    pub start_block: u64,
+    pub network: String,
+    pub execution_mode: Option<ExecutionMode>,
+    #[serde(flatten)]
+    pub concurrency: ConcurrencyConfig,
+    #[serde(default)]
+    pub direction: TransactionDirection,
+}
+
+impl TransactionHandlerConfig {
+    pub fn new(address: impl Into<String>, start_block: u64, network: impl Into<String>) -> Self {
+        TransactionHandlerConfig {
+            abi: None,
+            address: address.into(),
+            start_block,
+            network: network.into(),
+            execution_mode: None,
+            concurrency: ConcurrencyConfig::default(),
+            direction: TransactionDirection::default(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NetworkConfig {
     pub rpc_url: String,
+    /// Compute units refilled per second, each request costs one unless set
+    /// otherwise in `method_costs`.
     pub requests_per_second: u64,
+    /// Compute units that can be spent at once, defaults to `requests_per_second`.
+    pub burst: Option<u64>,
+    /// Compute units of each RPC method, as billed by the provider.
+    #[serde(default)]
+    pub method_costs: HashMap<String, u64>,
+    /// Uses `requests_per_second` as a ceiling, backing off when the RPC rate
+    /// limits the requests and recovering gradually.
+    #[serde(default)]
+    pub adaptive: bool,
+    /// Shares the rate limit with the other processes using the same key.
+    pub shared_rate_limit: Option<SharedRateLimitConfig>,
+}
+
+impl NetworkConfig {
+    pub fn new(rpc_url: impl Into<String>, requests_per_second: u64) -> Self {
+        NetworkConfig {
+            rpc_url: rpc_url.into(),
+            requests_per_second,
+            burst: None,
+            method_costs: HashMap::new(),
+            adaptive: false,
+            shared_rate_limit: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedRateLimitConfig {
+    /// Directory of the files where the rate limits are shared, defaults to
+    /// `ghost-crab` in the temporary directory.
+    pub path: Option<String>,
+    /// Name of the shared budget (e.g. the RPC key), defaults to the network name.
+    pub key: Option<String>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Requests go to the RPC, and cacheable responses are cached in `cache/<network>`.
+    #[default]
+    Live,
+    /// Every request and its response are captured in `fixtures/<network>`.
+    Record,
+    /// Requests are only served from `fixtures/<network>`, and fail when missing.
+    Replay,
+    /// Cacheable requests are only served from `cache/<network>`, and the highest
+    /// cached block is used as the chain head.
+    Offline,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum EvictionPolicy {
+    /// Evicts the entries of the oldest blocks first.
+    #[default]
+    OldestBlock,
+    /// Evicts the entries that were not read for the longest time first.
+    LeastRecentlyUsed,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionPolicy {
+    /// Whether the responses of the method are cached.
+    #[serde(default = "default_true")]
+    pub cache: bool,
+    /// Number of blocks, before the highest cached block of the method, whose entries are kept.
+    pub blocks: Option<u64>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackendKind {
+    RocksDb,
+    Filesystem,
+    Memory,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub mode: CacheMode,
+    /// Defaults to RocksDB, or to the filesystem when the `rocksdb` feature is disabled.
+    pub backend: Option<CacheBackendKind>,
+    /// Size of the cache of each network, in megabytes, above which entries are evicted.
+    pub max_size_mb: Option<u64>,
+    #[serde(default)]
+    pub eviction: EvictionPolicy,
+    /// Retention policy of each RPC method.
+    #[serde(default)]
+    pub retention: HashMap<String, RetentionPolicy>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
+pub struct GraphqlConfig {
+    /// Host the server binds to, `127.0.0.1` by default.
+    #[serde(default = "default_graphql_host")]
+    pub host: String,
+    pub port: u16,
+}
+
+fn default_graphql_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl GraphqlConfig {
+    /// Serves on the port of `127.0.0.1`.
+    pub fn new(port: u16) -> Self {
+        GraphqlConfig { host: default_graphql_host(), port }
+    }
+
+    /// Sets the host the server binds to, e.g. `0.0.0.0` to accept outside connections.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+}
+
+impl Default for GraphqlConfig {
+    /// Serves on `127.0.0.1:8000`.
+    fn default() -> Self {
+        GraphqlConfig::new(8000)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct Config {
+    pub database: Option<String>,
+    pub schema: Option<String>,
+    pub graphql: Option<GraphqlConfig>,
+    #[serde(default)]
+    pub cache: CacheConfig,
     pub data_sources: HashMap<String, DataSource>,
     pub templates: HashMap<String, Template>,
     pub networks: HashMap<String, NetworkConfig>,
     pub block_handlers: HashMap<String, BlockHandlerConfig>,
+    #[serde(default)]
+    pub transaction_handlers: HashMap<String, TransactionHandlerConfig>,
 }
 
 #[derive(Debug)]
@@ -84,23 +328,49 @@ impl std::fmt::Display for ConfigError {
 
 impl std::error::Error for ConfigError {}
 
+/// Environment variable with the path of the config file, which defaults to
+/// `config.json` in the current directory.
+pub const CONFIG_PATH_VAR: &str = "GHOST_CRAB_CONFIG";
+
 pub fn load() -> Result<Config, ConfigError> {
     dotenv().ok();
 
-    let config_path = get_config_path()?;
-    let config_string = read_config_file(&config_path)?;
+    let config_path = config_path()?;
+    load_from(&config_path)
+}
+
+/// Loads the config file at the path, replacing the environment variables.
+/// The path of the schema is resolved from the directory of the config file,
+/// so the macros and the indexer find the same schema.
+pub fn load_from(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+    dotenv().ok();
+
+    let path = path.as_ref();
+    let config_string = read_config_file(path)?;
     let mut config: Config = parse_config(&config_string)?;
     replace_env_vars(&mut config)?;
 
+    let config_dir = path.parent().unwrap_or(Path::new(""));
+    config.schema =
+        config.schema.map(|schema| config_dir.join(schema).to_string_lossy().into_owned());
+
     Ok(config)
 }
 
-fn get_config_path() -> Result<PathBuf, ConfigError> {
+/// Returns the path of the config file, set with `GHOST_CRAB_CONFIG` or
+/// `config.json` in the current directory. Relative paths are resolved from
+/// the current directory, while the macros resolve them from the directory of
+/// the crate.
+pub fn config_path() -> Result<PathBuf, ConfigError> {
     let current_dir = env::current_dir().map_err(|error| ConfigError::CurrentDirNotFound(error))?;
-    Ok(current_dir.join("config.json"))
+
+    match env::var(CONFIG_PATH_VAR) {
+        Ok(path) => Ok(current_dir.join(path)),
+        Err(_) => Ok(current_dir.join("config.json")),
+    }
 }
 
-fn read_config_file(path: &PathBuf) -> Result<String, ConfigError> {
+fn read_config_file(path: &Path) -> Result<String, ConfigError> {
     fs::read_to_string(path).map_err(|error| ConfigError::FileNotFound(error))
 }
 
@@ -118,3 +388,53 @@ fn replace_env_vars(config: &mut Config) -> Result<(), ConfigError> {
 
     Ok(())
 }
+
+/// Returns the database url of the config, replacing the environment variable.
+/// It is resolved by the indexer when it connects, as the macros load the
+/// config while compiling, where the variable may not be set.
+pub fn database_url(config: &Config) -> Result<Option<String>, ConfigError> {
+    match config.database.as_deref() {
+        Some(database) => match database.strip_prefix('$') {
+            Some(var) => {
+                env::var(var).map(Some).map_err(|_| ConfigError::EnvVarNotFound(var.to_string()))
+            }
+            None => Ok(Some(database.to_string())),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_the_database_from_the_environment() {
+        let config = Config {
+            database: Some("$GHOST_CRAB_TEST_DATABASE".to_string()),
+            ..Default::default()
+        };
+
+        assert!(matches!(database_url(&config), Err(ConfigError::EnvVarNotFound(_))));
+
+        env::set_var("GHOST_CRAB_TEST_DATABASE", "sqlite::memory:");
+        assert_eq!(database_url(&config).unwrap().as_deref(), Some("sqlite::memory:"));
+
+        let config =
+            Config { database: Some("sqlite://index.db".to_string()), ..Default::default() };
+        assert_eq!(database_url(&config).unwrap().as_deref(), Some("sqlite://index.db"));
+    }
+
+    #[test]
+    fn resolves_the_schema_from_the_config_directory() {
+        let dir = env::temp_dir().join("ghost-crab-config-test").join("indexer");
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("config.json");
+        let config = r#"{ "schema": "schema.json", "dataSources": {}, "templates": {}, "networks": {}, "blockHandlers": {} }"#;
+        fs::write(&path, config).unwrap();
+
+        let config = load_from(&path).unwrap();
+        assert_eq!(config.schema.map(PathBuf::from), Some(dir.join("schema.json")));
+    }
+}
@@ -0,0 +1,42 @@
+use crate::config::ConfigError;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::{env, fs};
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Address,
+    Bool,
+    Uint64,
+    Int64,
+    Uint256,
+    Int256,
+    Bytes,
+    Bytes32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EntityConfig {
+    pub fields: BTreeMap<String, FieldType>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Schema {
+    pub entities: HashMap<String, EntityConfig>,
+}
+
+/// Fields added to every entity to track where it was last written.
+pub const METADATA_FIELDS: &[&str] = &["id", "block_number", "log_index", "tx_hash"];
+
+pub fn load(path: impl AsRef<Path>) -> Result<Schema, ConfigError> {
+    let current_dir = env::current_dir().map_err(ConfigError::CurrentDirNotFound)?;
+    let schema_string =
+        fs::read_to_string(current_dir.join(path)).map_err(ConfigError::FileNotFound)?;
+
+    serde_json::from_str(&schema_string).map_err(ConfigError::InvalidConfig)
+}
@@ -1,39 +1,442 @@
 extern crate proc_macro;
-use ghost_crab_common::config;
+use ghost_crab_common::config::{self, Config};
+use ghost_crab_common::schema::{self, FieldType};
 use proc_macro::TokenStream;
-use proc_macro2::{Ident, Literal};
+use proc_macro2::{Ident, Literal, Span};
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, ItemFn};
+use serde_json::Value;
+use std::path::PathBuf;
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{ItemFn, LitBool, LitStr, Token};
 
 #[proc_macro_attribute]
 pub fn event_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
-    create_handler(metadata, input, false)
+    into_token_stream(create_handler(metadata, input, false, false))
 }
 
 #[proc_macro_attribute]
 pub fn template(metadata: TokenStream, input: TokenStream) -> TokenStream {
-    create_handler(metadata, input, true)
+    into_token_stream(create_handler(metadata, input, true, false))
+}
+
+#[proc_macro_attribute]
+pub fn batch_event_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
+    into_token_stream(create_handler(metadata, input, false, true))
 }
 
 #[proc_macro_attribute]
 pub fn block_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
-    let name = metadata.to_string();
-    let name = name.trim();
+    into_token_stream(create_block_handler(metadata, input))
+}
+
+#[proc_macro_attribute]
+pub fn transaction_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
+    into_token_stream(create_transaction_handler(metadata, input))
+}
+
+#[proc_macro]
+pub fn entities(input: TokenStream) -> TokenStream {
+    into_token_stream(create_entities(input.into()))
+}
+
+/// Returns the generated code, or the error as a compile error pointing at
+/// the offending tokens.
+fn into_token_stream(result: syn::Result<proc_macro2::TokenStream>) -> TokenStream {
+    TokenStream::from(result.unwrap_or_else(syn::Error::into_compile_error))
+}
+
+/// Named arguments following the source, e.g. `key = user`,
+/// `config = "indexer/config.json"`, `abi = "abis/Vault.json"` or
+/// `checked = false`.
+#[derive(Default)]
+struct Options {
+    key: Option<Ident>,
+    config: Option<LitStr>,
+    abi: Option<LitStr>,
+    /// Whether the source is checked against the config file, unless the
+    /// handler is configured in code.
+    checked: Option<LitBool>,
+}
+
+impl Options {
+    fn checked(&self) -> bool {
+        self.checked.as_ref().is_none_or(LitBool::value)
+    }
+
+    /// Parses the arguments, each preceded by a comma.
+    fn parse(input: ParseStream, allowed: &[&str]) -> syn::Result<Options> {
+        let mut options = Options::default();
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+
+            if input.is_empty() {
+                break;
+            }
+
+            options.parse_argument(input, allowed)?;
+        }
+
+        Ok(options)
+    }
+
+    fn parse_argument(&mut self, input: ParseStream, allowed: &[&str]) -> syn::Result<()> {
+        let argument: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+
+        if !allowed.iter().any(|name| argument == name) {
+            let expected: Vec<String> = allowed.iter().map(|name| format!("`{}`", name)).collect();
+
+            return Err(syn::Error::new(
+                argument.span(),
+                format!("Unknown argument `{}`, expected {}", argument, expected.join(" or ")),
+            ));
+        }
+
+        if argument == "key" {
+            self.key = Some(input.parse()?);
+        } else if argument == "config" {
+            self.config = Some(input.parse()?);
+        } else if argument == "checked" {
+            self.checked = Some(input.parse()?);
+        } else {
+            self.abi = Some(input.parse()?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Arguments of the event handlers: `Source.Event`, optionally followed by
+/// `key = field`, `config = "path"` and `abi = "path"`.
+struct EventHandlerArgs {
+    source: Ident,
+    event: Ident,
+    options: Options,
+}
+
+impl Parse for EventHandlerArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "The source is missing, expected `Source.Event`",
+            ));
+        }
+
+        let source: Ident = input.parse()?;
+
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                source.span(),
+                "The event name is missing, expected `Source.Event`",
+            ));
+        }
+
+        input.parse::<Token![.]>()?;
+        let event = input.parse()?;
+        let options = Options::parse(input, &["key", "config", "abi"])?;
+
+        Ok(EventHandlerArgs { source, event, options })
+    }
+}
+
+/// Arguments of the transaction handlers: `Source`, optionally followed by
+/// `.function` to only handle the calls of the function, `config = "path"`,
+/// `abi = "path"` and `checked = false`.
+struct TransactionHandlerArgs {
+    source: Ident,
+    function: Option<Ident>,
+    options: Options,
+}
+
+impl Parse for TransactionHandlerArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "The source is missing, expected `Source`",
+            ));
+        }
+
+        let source = input.parse()?;
+
+        let function = match input.parse::<Option<Token![.]>>()? {
+            Some(_) => Some(input.parse()?),
+            None => None,
+        };
+
+        let options = Options::parse(input, &["config", "abi", "checked"])?;
+
+        Ok(TransactionHandlerArgs { source, function, options })
+    }
+}
+
+/// Arguments of `entities!`: optionally `config = "path"`.
+struct EntitiesArgs {
+    options: Options,
+}
+
+impl Parse for EntitiesArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Options::default();
+
+        if !input.is_empty() {
+            options.parse_argument(input, &["config"])?;
+        }
+
+        if !input.is_empty() {
+            return Err(input.error("The metadata has too many values"));
+        }
+
+        Ok(EntitiesArgs { options })
+    }
+}
+
+/// Arguments of the block handlers: `Source`, optionally followed by
+/// `config = "path"` and `checked = false`.
+struct BlockHandlerArgs {
+    source: Ident,
+    options: Options,
+}
+
+impl Parse for BlockHandlerArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Err(syn::Error::new(
+                Span::call_site(),
+                "The source is missing, expected `Source`",
+            ));
+        }
+
+        let source = input.parse()?;
+
+        let options = Options::parse(input, &["config", "checked"])?;
 
-    if name.is_empty() {
-        panic!("The source is missing");
+        Ok(BlockHandlerArgs { source, options })
     }
+}
+
+/// Returns the path of a file given to a macro, relative to the directory of
+/// the crate like the paths given to `sol!`.
+fn crate_path(path: &str) -> PathBuf {
+    match std::env::var("CARGO_MANIFEST_DIR") {
+        Ok(manifest_dir) => PathBuf::from(manifest_dir).join(path),
+        Err(_) => PathBuf::from(path),
+    }
+}
+
+/// Returns the path of the config file given to the macro, or set with
+/// `GHOST_CRAB_CONFIG`, or `config.json`, from the directory of the crate.
+fn config_file(config: Option<String>, var: Option<String>) -> PathBuf {
+    crate_path(&config.or(var).unwrap_or_else(|| String::from("config.json")))
+}
+
+/// Loads the config file given to the macro, or the one set with
+/// `GHOST_CRAB_CONFIG`, or `config.json`. The relative paths are resolved from
+/// the directory of the crate, as the compiler runs from the root of the
+/// workspace. The returned code registers the file, so the crate is rebuilt
+/// when it changes.
+///
+/// A missing config file is an error, as the crate wouldn't be rebuilt once
+/// it is added. The handlers configured in code opt out explicitly instead.
+fn load_config(config: Option<&LitStr>) -> syn::Result<(Config, proc_macro2::TokenStream)> {
+    let span = config.map_or(Span::call_site(), LitStr::span);
+    let var = Literal::string(config::CONFIG_PATH_VAR);
+
+    let path = config_file(config.map(LitStr::value), std::env::var(config::CONFIG_PATH_VAR).ok());
+
+    if !path.exists() {
+        return Err(syn::Error::new(
+            span,
+            format!(
+                "{} not found, set the path of the config with `{}` or the `config` argument. Without a config file, e.g. when it is built in code, pass `abi` to the event and transaction handlers or `checked = false` to the block and transaction handlers",
+                path.display(),
+                config::CONFIG_PATH_VAR
+            ),
+        ));
+    }
+
+    let config = config::load_from(&path).map_err(|error| {
+        syn::Error::new(span, format!("Error loading {}: {}", path.display(), error))
+    })?;
+
+    let path = Literal::string(&path.to_string_lossy());
+
+    let tracking = quote! {
+        const _: &[u8] = include_bytes!(#path);
+        const _: Option<&str> = option_env!(#var);
+    };
+
+    Ok((config, tracking))
+}
+
+/// Returns an error pointing at the source, listing the sources of the kind
+/// defined in the config.json.
+fn source_not_found<'a>(
+    source: &Ident,
+    kind: &str,
+    available: impl Iterator<Item = &'a String>,
+) -> syn::Error {
+    syn::Error::new(
+        source.span(),
+        format!(
+            "{} `{}` not found in the config.json. Available: {}",
+            kind,
+            source,
+            list(available.map(String::as_str))
+        ),
+    )
+}
+
+/// Returns the sorted names separated by commas, or `none`.
+fn list<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    let mut names: Vec<&str> = names.collect();
+    names.sort();
+    names.dedup();
+
+    match names.is_empty() {
+        true => String::from("none"),
+        false => names.join(", "),
+    }
+}
+
+/// Reads the items of the ABI, pointing at the name on errors. The path is
+/// resolved like `sol!` does, from the directory of the crate.
+fn read_abi_items(abi: &str, name: &Ident) -> syn::Result<Vec<Value>> {
+    let path = crate_path(abi);
+
+    let error = |message: String| syn::Error::new(name.span(), message);
+
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|io_error| error(format!("Error reading the ABI {}: {}", abi, io_error)))?;
+
+    let json: Value = serde_json::from_str(&contents)
+        .map_err(|json_error| error(format!("Invalid ABI {}: {}", abi, json_error)))?;
+
+    // The ABI itself, or an artifact with an `abi` field
+    let items = json.get("abi").unwrap_or(&json).as_array().cloned().unwrap_or_default();
+
+    Ok(items)
+}
+
+/// Checks that the ABI has an item of the kind (`event` or `function`) with
+/// the name, pointing at the name otherwise.
+fn check_abi_item(abi: &str, kind: &str, name: &Ident) -> syn::Result<()> {
+    let items = read_abi_items(abi, name)?;
+
+    let names: Vec<&str> = items
+        .iter()
+        .filter(|item| item.get("type").and_then(Value::as_str) == Some(kind))
+        .filter_map(|item| item.get("name").and_then(Value::as_str))
+        .collect();
+
+    if names.iter().any(|item_name| name == item_name) {
+        return Ok(());
+    }
+
+    Err(syn::Error::new(
+        name.span(),
+        format!(
+            "The {} `{}` is not in the ABI {}. Available: {}",
+            kind,
+            name,
+            abi,
+            list(names.into_iter())
+        ),
+    ))
+}
 
-    let config = config::load().expect("config.json not found");
-    let _ = config.block_handlers.get(name).expect("BlockHandler not found in the config.json");
-    let name = Literal::string(name);
+/// Returns the index of the topic holding the key field of the event, which
+/// must be indexed so the key is read without decoding the log.
+fn key_topic(abi: &str, event: &Ident, field: &Ident) -> syn::Result<usize> {
+    let items = read_abi_items(abi, event)?;
+
+    let event_item = items.iter().find(|item| {
+        item.get("type").and_then(Value::as_str) == Some("event")
+            && item.get("name").and_then(Value::as_str).is_some_and(|name| event == name)
+    });
+
+    let inputs = event_item
+        .and_then(|item| item.get("inputs"))
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let anonymous =
+        event_item.and_then(|item| item.get("anonymous")).and_then(Value::as_bool).unwrap_or(false);
+
+    let indexed: Vec<&str> = inputs
+        .iter()
+        .filter(|input| input.get("indexed").and_then(Value::as_bool) == Some(true))
+        .filter_map(|input| input.get("name").and_then(Value::as_str))
+        .collect();
+
+    // The first topic of an event that is not anonymous is its signature
+    if let Some(position) = indexed.iter().position(|name| field == name) {
+        return Ok(position + usize::from(!anonymous));
+    }
+
+    let is_field = inputs
+        .iter()
+        .any(|input| input.get("name").and_then(Value::as_str).is_some_and(|name| field == name));
+
+    let message = match is_field {
+        true => format!(
+            "The key field `{}` of `{}` must be indexed. Indexed fields: {}",
+            field,
+            event,
+            list(indexed.into_iter())
+        ),
+        false => format!(
+            "The key field `{}` is not a field of `{}`. Indexed fields: {}",
+            field,
+            event,
+            list(indexed.into_iter())
+        ),
+    };
+
+    Err(syn::Error::new(field.span(), message))
+}
+
+fn create_block_handler(
+    metadata: TokenStream,
+    input: TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let BlockHandlerArgs { source, options } = syn::parse(metadata)?;
+    let parsed: ItemFn = syn::parse(input)?;
+
+    let name = source.to_string();
+
+    // With `checked = false`, the config is built in code and the name is not checked
+    let tracking = match options.checked() {
+        true => {
+            let (config, tracking) = load_config(options.config.as_ref())?;
+
+            if !config.block_handlers.contains_key(&name) {
+                return Err(source_not_found(
+                    &source,
+                    "BlockHandler",
+                    config.block_handlers.keys(),
+                ));
+            }
+
+            tracking
+        }
+        false => quote! {},
+    };
+
+    let name = Literal::string(&name);
 
-    let parsed = parse_macro_input!(input as ItemFn);
     let fn_name = parsed.sig.ident.clone();
-    let fn_body = parsed.block;
+    let fn_body = parsed.block.clone();
     let fn_args = parsed.sig.inputs.clone();
+    let fn_output = get_output_type(&parsed);
+
+    Ok(quote! {
+        #tracking
 
-    TokenStream::from(quote! {
         pub struct #fn_name;
 
         impl #fn_name {
@@ -44,8 +447,9 @@ pub fn block_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
 
         #[async_trait]
         impl BlockHandler for #fn_name {
-            async fn handle(&self, #fn_args) {
-                #fn_body
+            async fn handle(&self, #fn_args) -> HandlerResult {
+                let result: #fn_output = async move #fn_body.await;
+                IntoHandlerResult::into_handler_result(result)
             }
 
             fn name(&self) -> String {
@@ -55,72 +459,422 @@ pub fn block_handler(metadata: TokenStream, input: TokenStream) -> TokenStream {
     })
 }
 
-fn get_source_and_event(metadata: TokenStream) -> (String, Ident) {
-    let metadata_string = metadata.to_string();
-    let mut metadata_split = metadata_string.split('.');
+fn create_transaction_handler(
+    metadata: TokenStream,
+    input: TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let TransactionHandlerArgs { source: source_name, function, options } = syn::parse(metadata)?;
+    let parsed: ItemFn = syn::parse(input)?;
+
+    let name = source_name.to_string();
+
+    // With the abi argument or `checked = false`, the config is not read, e.g. when it is built in code
+    let (abi, tracking) = match (&options.abi, options.checked()) {
+        (Some(abi), _) => (Some(abi.value()), quote! {}),
+        (None, false) => (None, quote! {}),
+        (None, true) => {
+            let (config, tracking) = load_config(options.config.as_ref())?;
+
+            let source = config.transaction_handlers.get(&name).ok_or_else(|| {
+                source_not_found(
+                    &source_name,
+                    "TransactionHandler",
+                    config.transaction_handlers.keys(),
+                )
+            })?;
+
+            (source.abi.clone(), tracking)
+        }
+    };
+
+    let fn_name = parsed.sig.ident.clone();
+    let fn_args = parsed.sig.inputs.clone();
+    let fn_body = parsed.block.clone();
+    let fn_output = get_output_type(&parsed);
+    let ctx = get_context_identifier(&parsed)?;
+
+    let data_source = Literal::string(&name);
+
+    let (contract, decoded_call, function_selector) = match function {
+        Some(function_name) => {
+            let abi = abi.as_ref().ok_or_else(|| {
+                syn::Error::new(
+                    function_name.span(),
+                    format!(
+                        "The function name requires an abi for `{}` in the config.json or the `abi` argument",
+                        source_name
+                    ),
+                )
+            })?;
+
+            check_abi_item(abi, "function", &function_name)?;
+
+            let abi = Literal::string(abi);
+            let contract_name = format_ident!("{}Contract", fn_name);
+            let call_name = format_ident!("{}Call", function_name);
+
+            (
+                quote! {
+                    sol!(
+                        #[sol(rpc)]
+                        #contract_name,
+                        #abi
+                    );
+                },
+                quote! {
+                    // Malformed calldata still matches the selector, the transaction is skipped
+                    let call = match #contract_name::#call_name::abi_decode(&#ctx.transaction.input, false) {
+                        Ok(call) => call,
+                        Err(error) => {
+                            eprintln!(
+                                "[{}] Skipping transaction {}, error decoding its input: {}",
+                                #data_source,
+                                #ctx.transaction.hash,
+                                error
+                            );
+
+                            return Ok(());
+                        }
+                    };
+                },
+                quote! {
+                    Some(#contract_name::#call_name::SELECTOR)
+                },
+            )
+        }
+        None => (quote! {}, quote! {}, quote! { None }),
+    };
+
+    Ok(quote! {
+        #tracking
+
+        #contract
+
+        pub struct #fn_name;
+
+        impl #fn_name {
+            pub fn new() -> Arc<Box<(dyn TransactionHandler + Send + Sync)>> {
+                Arc::new(Box::new(#fn_name {}))
+            }
+        }
+
+        #[async_trait]
+        impl TransactionHandler for #fn_name {
+            async fn handle(&self, #fn_args) -> HandlerResult {
+                #decoded_call
+
+                let result: #fn_output = async move #fn_body.await;
+                IntoHandlerResult::into_handler_result(result)
+            }
+
+            fn name(&self) -> String {
+                String::from(#data_source)
+            }
+
+            fn function_selector(&self) -> Option<[u8; 4]> {
+                #function_selector
+            }
+        }
+    })
+}
+
+fn create_entities(input: proc_macro2::TokenStream) -> syn::Result<proc_macro2::TokenStream> {
+    let EntitiesArgs { options } = syn::parse2(input)?;
+    let (config, tracking) = load_config(options.config.as_ref())?;
+
+    // The schema is set in the config, so its errors point at the config argument
+    let span = options.config.as_ref().map_or(Span::call_site(), LitStr::span);
+
+    let schema_path = config
+        .schema
+        .ok_or_else(|| syn::Error::new(span, "The schema is missing in the config.json"))?;
+
+    // Resolved from the directory of the config file, like the indexer does at runtime
+    let path = PathBuf::from(&schema_path);
+
+    let schema = schema::load(&path).map_err(|error| {
+        syn::Error::new(span, format!("Error loading the schema {}: {}", schema_path, error))
+    })?;
+
+    let path = Literal::string(&path.to_string_lossy());
+
+    let mut entity_names: Vec<&String> = schema.entities.keys().collect();
+    entity_names.sort();
 
-    let name = metadata_split.next().expect("The source is missing");
-    let name = String::from(name.trim());
+    let mut entities = Vec::new();
 
-    if name.is_empty() {
-        panic!("The source is empty");
+    for entity_name in entity_names {
+        let entity = &schema.entities[entity_name];
+        let struct_name = format_ident!("{}", entity_name);
+        let entity_literal = Literal::string(entity_name);
+
+        let mut fields = Vec::new();
+
+        for (field_name, field_type) in &entity.fields {
+            if schema::METADATA_FIELDS.contains(&field_name.as_str()) {
+                return Err(syn::Error::new(
+                    span,
+                    format!("The field {} of {} is reserved", field_name, entity_name),
+                ));
+            }
+
+            let field_name = format_ident!("{}", field_name);
+            let field_type = get_field_type(*field_type);
+
+            fields.push(quote! {
+                pub #field_name: #field_type,
+            });
+        }
+
+        entities.push(quote! {
+            #[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+            #[serde(crate = "serde")]
+            pub struct #struct_name {
+                pub id: String,
+                #(#fields)*
+                pub block_number: u64,
+                pub log_index: Option<u64>,
+                pub tx_hash: Option<alloy::primitives::TxHash>,
+            }
+
+            impl #struct_name {
+                pub const ENTITY: &'static str = #entity_literal;
+
+                pub async fn load(
+                    store: &StoreTransaction,
+                    id: &str,
+                ) -> Result<Option<#struct_name>, StoreError> {
+                    store.load(Self::ENTITY, id).await
+                }
+
+                /// Saves the entity, setting the metadata from the handler context.
+                pub fn save(&mut self, store: &StoreTransaction) -> Result<(), StoreError> {
+                    let metadata = store.metadata();
+
+                    self.block_number = metadata.block_number;
+                    self.log_index = metadata.log_index;
+                    self.tx_hash = metadata.tx_hash;
+
+                    store.save(Self::ENTITY, &self.id, self)
+                }
+
+                /// Loads the entity, applies the update and saves it, returning the
+                /// updated entity or `None` if it doesn't exist.
+                pub async fn update<F: FnOnce(&mut #struct_name)>(
+                    store: &StoreTransaction,
+                    id: &str,
+                    update: F,
+                ) -> Result<Option<#struct_name>, StoreError> {
+                    let Some(mut entity) = Self::load(store, id).await? else {
+                        return Ok(None);
+                    };
+
+                    update(&mut entity);
+                    entity.save(store)?;
+
+                    Ok(Some(entity))
+                }
+
+                pub fn delete(store: &StoreTransaction, id: &str) -> Result<(), StoreError> {
+                    store.delete(Self::ENTITY, id)
+                }
+            }
+        });
     }
 
-    let event_name = metadata_split.next().expect("The event name is missing");
-    let event_name = String::from(event_name.trim());
+    Ok(quote! {
+        #tracking
 
-    if event_name.is_empty() {
-        panic!("The event name is empty");
+        const _: &[u8] = include_bytes!(#path);
+
+        #(#entities)*
+    })
+}
+
+fn get_field_type(field_type: FieldType) -> proc_macro2::TokenStream {
+    match field_type {
+        FieldType::String => quote! { String },
+        FieldType::Address => quote! { alloy::primitives::Address },
+        FieldType::Bool => quote! { bool },
+        FieldType::Uint64 => quote! { u64 },
+        FieldType::Int64 => quote! { i64 },
+        FieldType::Uint256 => quote! { alloy::primitives::U256 },
+        FieldType::Int256 => quote! { alloy::primitives::I256 },
+        FieldType::Bytes => quote! { alloy::primitives::Bytes },
+        FieldType::Bytes32 => quote! { alloy::primitives::B256 },
     }
+}
 
-    // Checks that the metadata does not have more than 3 comma separated values
-    let should_be_none = metadata_split.next();
-    if should_be_none.is_some() {
-        panic!("The metadata has too many values");
+/// Returns the return type of the handler, which is either nothing or a
+/// `Result` whose error stops the source.
+fn get_output_type(parsed: &ItemFn) -> proc_macro2::TokenStream {
+    match &parsed.sig.output {
+        syn::ReturnType::Default => quote! { () },
+        syn::ReturnType::Type(_, output) => quote! { #output },
     }
+}
+
+fn get_context_identifier(parsed: &ItemFn) -> syn::Result<Ident> {
+    let error = || {
+        syn::Error::new(
+            parsed.sig.inputs.span(),
+            "The handler must take the context as its first argument, e.g. `ctx: EventContext`",
+        )
+    };
 
-    let event_name = syn::Ident::new(&event_name, proc_macro2::Span::call_site());
-    return (name, event_name);
+    match parsed.sig.inputs.first().ok_or_else(|| {
+        syn::Error::new(parsed.sig.span(), "The handler must take the context as its argument")
+    })? {
+        syn::FnArg::Typed(pat_type) => match &*pat_type.pat {
+            syn::Pat::Ident(pat_ident) => Ok(pat_ident.ident.clone()),
+            _ => Err(error()),
+        },
+        syn::FnArg::Receiver(_) => Err(error()),
+    }
 }
 
-fn get_context_identifier(parsed: ItemFn) -> Ident {
-    let first_input = parsed.sig.inputs[0].clone();
+fn create_handler(
+    metadata: TokenStream,
+    input: TokenStream,
+    is_template: bool,
+    is_batch: bool,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let EventHandlerArgs { source, event: event_name, options } = syn::parse(metadata)?;
+    let Options { key, config, abi, .. } = options;
+    let parsed: ItemFn = syn::parse(input)?;
 
-    let ctx = if let syn::FnArg::Typed(pat_type) = first_input {
-        if let syn::Pat::Ident(pat_ident) = *pat_type.pat {
-            pat_ident.ident
-        } else {
-            panic!("Malformed handler function arguments")
+    if let (true, Some(key)) = (is_batch, &key) {
+        return Err(syn::Error::new(
+            key.span(),
+            "The key argument is not supported by batch handlers",
+        ));
+    }
+
+    let name = source.to_string();
+
+    // With the abi argument, the config is not read, e.g. when it is built in code
+    let (abi, tracking) = match abi {
+        Some(abi) => (abi.value(), quote! {}),
+        None => {
+            let (config, tracking) = load_config(config.as_ref())?;
+
+            let abi = if is_template {
+                let template = config.templates.get(&name).ok_or_else(|| {
+                    source_not_found(&source, "Template", config.templates.keys())
+                })?;
+
+                template.abi.clone()
+            } else {
+                let data_source = config.data_sources.get(&name).ok_or_else(|| {
+                    source_not_found(&source, "DataSource", config.data_sources.keys())
+                })?;
+
+                data_source.abi.clone()
+            };
+
+            (abi, tracking)
         }
-    } else {
-        panic!("Malformed handler function arguments")
     };
 
-    return ctx;
-}
+    check_abi_item(&abi, "event", &event_name)?;
 
-fn create_handler(metadata: TokenStream, input: TokenStream, is_template: bool) -> TokenStream {
-    let (name, event_name) = get_source_and_event(metadata);
-    let config = config::load().expect("config.json not found");
+    // Without a key field, the default key of the trait is used
+    let key = match key {
+        Some(field) => {
+            let topic = key_topic(&abi, &event_name, &field)?;
 
-    let abi = if is_template {
-        let source = config.templates.get(&name).expect("Source not found.");
-        Literal::string(&source.abi)
-    } else {
-        let source = config.data_sources.get(&name).expect("Source not found.");
-        Literal::string(&source.abi)
+            Some(quote! {
+                fn key(&self, log: &alloy::rpc::types::eth::Log) -> alloy::primitives::B256 {
+                    match log.topics().get(#topic) {
+                        Some(topic) => *topic,
+                        // The log can't be decoded and is skipped, it doesn't wait for the other logs
+                        None => alloy::primitives::keccak256(
+                            [
+                                log.transaction_hash.unwrap_or_default().as_slice(),
+                                &log.log_index.unwrap_or_default().to_be_bytes(),
+                            ]
+                            .concat(),
+                        ),
+                    }
+                }
+            })
+        }
+        None => None,
     };
 
-    let parsed = parse_macro_input!(input as ItemFn);
+    let abi = Literal::string(&abi);
+
     let fn_name = parsed.sig.ident.clone();
     let fn_args = parsed.sig.inputs.clone();
     let fn_body = parsed.block.clone();
-    let ctx = get_context_identifier(parsed);
+    let fn_output = get_output_type(&parsed);
+    let ctx = get_context_identifier(&parsed)?;
 
     let contract_name = format_ident!("{}Contract", fn_name);
     let data_source = Literal::string(&name);
 
-    TokenStream::from(quote! {
+    let handle = if is_batch {
+        quote! {
+            async fn handle(&self, ctx: EventContext) -> HandlerResult {
+                self.handle_batch(ctx.into()).await
+            }
+
+            fn batch(&self) -> bool {
+                true
+            }
+
+            async fn handle_batch(&self, #fn_args) -> HandlerResult {
+                let events = #ctx
+                    .logs
+                    .iter()
+                    .filter_map(|log| match log.log_decode::<#contract_name::#event_name>() {
+                        Ok(decoded_log) => Some(decoded_log),
+                        Err(error) => {
+                            eprintln!(
+                                "[{}] Skipping a log of transaction {}, error decoding its data: {}",
+                                #data_source,
+                                log.transaction_hash.unwrap_or_default(),
+                                error
+                            );
+
+                            None
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                let result: #fn_output = async move #fn_body.await;
+                IntoHandlerResult::into_handler_result(result)
+            }
+        }
+    } else {
+        quote! {
+            async fn handle(&self, #fn_args) -> HandlerResult {
+                // A log matching the signature with other indexed fields can't be decoded, it is skipped
+                let decoded_log = match #ctx.log.log_decode::<#contract_name::#event_name>() {
+                    Ok(decoded_log) => decoded_log,
+                    Err(error) => {
+                        eprintln!(
+                            "[{}] Skipping a log of transaction {}, error decoding its data: {}",
+                            #data_source,
+                            #ctx.log.transaction_hash.unwrap_or_default(),
+                            error
+                        );
+
+                        return Ok(());
+                    }
+                };
+
+                let event = decoded_log.data();
+
+                let result: #fn_output = async move #fn_body.await;
+                IntoHandlerResult::into_handler_result(result)
+            }
+        }
+    };
+
+    Ok(quote! {
+        #tracking
+
         sol!(
             #[sol(rpc)]
             #contract_name,
@@ -137,16 +891,7 @@ fn create_handler(metadata: TokenStream, input: TokenStream, is_template: bool)
 
         #[async_trait]
         impl EventHandler for #fn_name {
-            async fn handle(&self, #fn_args) {
-                let decoded_log = #ctx
-                    .log
-                    .log_decode::<#contract_name::#event_name>()
-                    .expect("Error decoding log data");
-
-                let event = decoded_log.data();
-
-                #fn_body
-            }
+            #handle
 
             fn name(&self) -> String {
                 String::from(#data_source)
@@ -155,6 +900,171 @@ fn create_handler(metadata: TokenStream, input: TokenStream, is_template: bool)
             fn event_signature(&self) -> String {
                 #contract_name::#event_name::SIGNATURE.to_string()
             }
+
+            #key
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ident(name: &str) -> Ident {
+        Ident::new(name, Span::call_site())
+    }
+
+    fn abi_file(events: Value) -> String {
+        let path =
+            std::env::temp_dir().join(format!("ghost-crab-macros-abi-{}.json", std::process::id()));
+        std::fs::write(&path, events.to_string()).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn parses_the_opt_out_of_the_config() {
+        let args: BlockHandlerArgs = syn::parse2(quote! { Stader, checked = false }).unwrap();
+        assert!(!args.options.checked());
+
+        let args: BlockHandlerArgs = syn::parse2(quote! { Stader }).unwrap();
+        assert!(args.options.checked());
+
+        let args = syn::parse2::<EventHandlerArgs>(quote! { Vault.Deposited, checked = false });
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn resolves_the_config_from_the_crate() {
+        let crate_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+        assert_eq!(config_file(None, None), crate_dir.join("config.json"));
+        assert_eq!(
+            config_file(None, Some("indexers/etherfi.json".to_string())),
+            crate_dir.join("indexers/etherfi.json")
+        );
+        assert_eq!(
+            config_file(Some("config.json".to_string()), Some("/etc/indexer.json".to_string())),
+            crate_dir.join("config.json")
+        );
+        assert_eq!(
+            config_file(None, Some("/etc/indexer.json".to_string())),
+            PathBuf::from("/etc/indexer.json")
+        );
+    }
+
+    #[test]
+    fn finds_the_topic_of_the_key() {
+        let abi = abi_file(serde_json::json!([{
+            "type": "event",
+            "name": "Deposited",
+            "anonymous": false,
+            "inputs": [
+                { "name": "vault", "type": "address", "indexed": true },
+                { "name": "assets", "type": "uint256", "indexed": false },
+                { "name": "user", "type": "address", "indexed": true }
+            ]
+        }]));
+
+        let event = ident("Deposited");
+
+        assert_eq!(key_topic(&abi, &event, &ident("vault")).unwrap(), 1);
+        assert_eq!(key_topic(&abi, &event, &ident("user")).unwrap(), 2);
+
+        let error = key_topic(&abi, &event, &ident("assets")).unwrap_err();
+        assert!(error.to_string().contains("must be indexed"));
+
+        let error = key_topic(&abi, &event, &ident("owner")).unwrap_err();
+        assert!(error.to_string().contains("is not a field"));
+
+        std::fs::remove_file(abi).unwrap();
+    }
+
+    /// Writes a config.json and the schema in a directory of the test,
+    /// returning the `config` argument of the macro.
+    fn entities_config(test: &str, schema: Value) -> String {
+        let dir =
+            std::env::temp_dir().join(format!("ghost-crab-macros-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = serde_json::json!({
+            "schema": "schema.json",
+            "dataSources": {},
+            "templates": {},
+            "networks": {},
+            "blockHandlers": {}
+        });
+
+        std::fs::write(dir.join("config.json"), config.to_string()).unwrap();
+        std::fs::write(dir.join("schema.json"), schema.to_string()).unwrap();
+
+        format!("config = {:?}", dir.join("config.json").to_string_lossy())
+    }
+
+    #[test]
+    fn generates_a_field_of_every_type() {
+        let args = entities_config(
+            "fields",
+            serde_json::json!({
+                "entities": {
+                    "Vault": {
+                        "fields": {
+                            "name": "string",
+                            "owner": "address",
+                            "paused": "bool",
+                            "deposits": "uint64",
+                            "delta": "int64",
+                            "assets": "uint256",
+                            "pnl": "int256",
+                            "data": "bytes",
+                            "salt": "bytes32"
+                        }
+                    }
+                }
+            }),
+        );
+
+        let output = create_entities(syn::parse_str(&args).unwrap()).unwrap().to_string();
+
+        let fields = [
+            quote! { pub name: String, },
+            quote! { pub owner: alloy::primitives::Address, },
+            quote! { pub paused: bool, },
+            quote! { pub deposits: u64, },
+            quote! { pub delta: i64, },
+            quote! { pub assets: alloy::primitives::U256, },
+            quote! { pub pnl: alloy::primitives::I256, },
+            quote! { pub data: alloy::primitives::Bytes, },
+            quote! { pub salt: alloy::primitives::B256, },
+        ];
+
+        assert!(output.contains(&quote! { pub struct Vault }.to_string()));
+
+        for field in fields {
+            assert!(output.contains(&field.to_string()), "{} is missing", field);
+        }
+    }
+
+    #[test]
+    fn points_the_schema_errors_at_the_config() {
+        let invalid_type = serde_json::json!({
+            "entities": { "Vault": { "fields": { "assets": "uint128" } } }
+        });
+        let reserved_field = serde_json::json!({
+            "entities": { "Vault": { "fields": { "block_number": "uint64" } } }
+        });
+
+        for (test, schema, message) in [
+            ("invalid-type", invalid_type, "Error loading the schema"),
+            ("reserved-field", reserved_field, "The field block_number of Vault is reserved"),
+        ] {
+            let args = entities_config(test, schema);
+            let error = create_entities(syn::parse_str(&args).unwrap()).unwrap_err();
+
+            assert!(error.to_string().contains(message), "{}", error);
+
+            // The config literal follows `config = `
+            let start = error.span().start();
+            assert_eq!((start.line, start.column), (1, 9));
+        }
+    }
+}
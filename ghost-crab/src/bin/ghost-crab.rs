@@ -0,0 +1,226 @@
+use ghost_crab::config::CacheBackendKind;
+use ghost_crab::indexer::cache::{self, CacheBackendInstance, PruneFilter, DEFAULT_BACKEND};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process::exit;
+
+const USAGE: &str = "Usage: ghost-crab cache <command> <network> [options]
+
+Commands:
+  stats <network>                      Count the entries and their size by RPC method
+  inspect <network>                    List the entries [--method <method>]
+  lookup <network> <method> [params]   Print the cached response of a request
+  prune <network>                      Remove entries [--method <method>] [--from-block <n>] [--to-block <n>]
+  export <network> <file>              Write the entries to a portable file
+  import <network> <file>              Read the entries of an exported file
+
+Options:
+  --dir <dir>                          Directory of the caches (default: cache)
+  --backend <backend>                  Cache backend: rocksdb or filesystem (default: rocksdb when enabled)";
+
+struct Args {
+    positional: Vec<String>,
+    dir: String,
+    backend: CacheBackendKind,
+    method: Option<String>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        positional: Vec::new(),
+        dir: "cache".to_string(),
+        backend: DEFAULT_BACKEND,
+        method: None,
+        from_block: None,
+        to_block: None,
+    };
+
+    let mut iter = std::env::args().skip(1);
+
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| iter.next().ok_or(format!("Missing value for {}", name));
+
+        match arg.as_str() {
+            "--dir" => args.dir = value("--dir")?,
+            "--backend" => {
+                let backend = serde_json::Value::String(value("--backend")?);
+                args.backend = serde_json::from_value(backend).map_err(|_| "Invalid --backend")?;
+
+                // A memory cache would be empty and dropped once the command is done
+                if args.backend == CacheBackendKind::Memory {
+                    return Err(
+                        "The memory backend has no cache to manage, use rocksdb or filesystem"
+                            .to_string(),
+                    );
+                }
+            }
+            "--method" => args.method = Some(value("--method")?),
+            "--from-block" => {
+                let from_block = value("--from-block")?;
+                args.from_block = Some(from_block.parse().map_err(|_| "Invalid --from-block")?);
+            }
+            "--to-block" => {
+                let to_block = value("--to-block")?;
+                args.to_block = Some(to_block.parse().map_err(|_| "Invalid --to-block")?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => args.positional.push(arg),
+        }
+    }
+
+    Ok(args)
+}
+
+fn cache_dir(args: &Args, network: &str) -> Result<PathBuf, String> {
+    let path = std::env::current_dir().map_err(|error| error.to_string())?.join(&args.dir);
+    Ok(path.join(network))
+}
+
+fn cache_path(args: &Args, network: &str) -> Result<PathBuf, String> {
+    let path = cache_dir(args, network)?;
+
+    if !path.exists() {
+        return Err(format!("Cache not found: {}", path.display()));
+    }
+
+    Ok(path)
+}
+
+fn open(args: &Args, network: &str) -> Result<CacheBackendInstance, String> {
+    let path = cache_path(args, network)?;
+    cache::open_cache(&path, args.backend).map_err(|error| error.to_string())
+}
+
+/// Opens the cache without migrating it nor taking its lock, for the commands
+/// that only read it, e.g. while the indexer is running.
+fn open_read_only(args: &Args, network: &str) -> Result<CacheBackendInstance, String> {
+    let path = cache_path(args, network)?;
+    cache::open_cache_read_only(&path, args.backend).map_err(|error| error.to_string())
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1_023 => format!("{} B", bytes),
+        1_024..=1_048_575 => format!("{:.1} KB", bytes as f64 / 1_024.0),
+        1_048_576..=1_073_741_823 => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+        _ => format!("{:.1} GB", bytes as f64 / 1_073_741_824.0),
+    }
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let positional: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+
+    match positional.as_slice() {
+        ["cache", "stats", network] => {
+            let db = open_read_only(&args, network)?;
+            let stats = cache::stats(db.as_ref()).map_err(|error| error.to_string())?;
+
+            println!("{:<32} {:>12} {:>12}", "METHOD", "ENTRIES", "SIZE");
+
+            for (method, method_stats) in &stats {
+                println!(
+                    "{:<32} {:>12} {:>12}",
+                    method,
+                    method_stats.entries,
+                    format_size(method_stats.bytes)
+                );
+            }
+
+            let entries: u64 = stats.values().map(|method_stats| method_stats.entries).sum();
+            let size = cache::size(db.as_ref()).map_err(|error| error.to_string())?;
+
+            println!("{:<32} {:>12} {:>12}", "TOTAL (on disk)", entries, format_size(size));
+        }
+        ["cache", "inspect", network] => {
+            let db = open_read_only(&args, network)?;
+
+            for entry in db.entries(b"") {
+                let (key, value) = entry.map_err(|error| error.to_string())?;
+                let key = String::from_utf8_lossy(&key);
+
+                if key.starts_with("ghost_crab:") {
+                    continue;
+                }
+
+                if let Some(method) = &args.method {
+                    if !key.starts_with(&format!("{method}:")) {
+                        continue;
+                    }
+                }
+
+                println!("{} {}", key, format_size(value.len() as u64));
+            }
+        }
+        ["cache", "lookup", network, method, params @ ..] => {
+            let db = open_read_only(&args, network)?;
+
+            let params: Value = match params {
+                [] => Value::Null,
+                [params] => serde_json::from_str(params)
+                    .map_err(|error| format!("Invalid params: {}", error))?,
+                _ => return Err(USAGE.to_string()),
+            };
+
+            let key = cache::cache_key(method, &params);
+
+            match db.get(key.as_bytes()).map_err(|error| error.to_string())? {
+                Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+                None => return Err(format!("Request not found in the cache: {}", key)),
+            }
+        }
+        ["cache", "prune", network] => {
+            let filter = PruneFilter {
+                method: args.method.clone(),
+                from_block: args.from_block,
+                to_block: args.to_block,
+            };
+
+            if filter.method.is_none() && filter.from_block.is_none() && filter.to_block.is_none() {
+                return Err("Specify --method, --from-block or --to-block to prune".to_string());
+            }
+
+            let db = open(&args, network)?;
+            let pruned = cache::prune(db.as_ref(), &filter).map_err(|error| error.to_string())?;
+
+            println!("Pruned {} entries", pruned);
+        }
+        ["cache", "export", network, file] => {
+            let db = open_read_only(&args, network)?;
+            let file = File::create(file).map_err(|error| error.to_string())?;
+
+            let exported = cache::export(db.as_ref(), &mut BufWriter::new(file))
+                .map_err(|error| error.to_string())?;
+
+            println!("Exported {} entries", exported);
+        }
+        ["cache", "import", network, file] => {
+            // The entries can be imported into a new cache
+            std::fs::create_dir_all(cache_dir(&args, network)?)
+                .map_err(|error| error.to_string())?;
+
+            let db = open(&args, network)?;
+            let file = File::open(file).map_err(|error| error.to_string())?;
+
+            let imported = cache::import(db.as_ref(), BufReader::new(file))
+                .map_err(|error| error.to_string())?;
+
+            println!("Imported {} entries", imported);
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let result = parse_args().and_then(run);
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        exit(1);
+    }
+}
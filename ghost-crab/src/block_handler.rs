@@ -1,19 +1,27 @@
+use crate::event_handler::HandlerResult;
 use crate::indexer::rpc_manager::Provider;
 use crate::indexer::templates::TemplateManager;
 use crate::latest_block_manager::LatestBlockManager;
+use crate::multicall::Multicall;
+use crate::pending_ranges::PendingRanges;
+use crate::store::{Metadata, Store, StoreTransaction};
+use alloy::eips::BlockId;
 use alloy::providers::Provider as AlloyProvider;
 use alloy::rpc::types::eth::Block;
 use alloy::rpc::types::eth::BlockNumberOrTag;
 use alloy::transports::TransportError;
 use async_trait::async_trait;
-use ghost_crab_common::config::BlockHandlerConfig;
 use ghost_crab_common::config::ExecutionMode;
+use ghost_crab_common::config::{
+    BlockHandlerConfig, ConcurrencyConfig, DEFAULT_BLOCK_PENDING_RANGES,
+};
 use std::sync::Arc;
 use std::time::Duration;
 
 pub struct BlockContext {
     pub provider: Provider,
     pub templates: TemplateManager,
+    pub store: StoreTransaction,
     pub block_number: u64,
 }
 
@@ -23,13 +31,19 @@ impl BlockContext {
             .get_block_by_number(BlockNumberOrTag::Number(self.block_number), hydrate)
             .await
     }
+
+    /// Returns a helper that aggregates the `eth_call`s made concurrently at
+    /// this block into a single Multicall3 call.
+    pub fn multicall(&self) -> Multicall {
+        Multicall::new(self.provider.clone(), BlockId::number(self.block_number))
+    }
 }
 
 pub type BlockHandlerInstance = Arc<Box<(dyn BlockHandler + Send + Sync)>>;
 
 #[async_trait]
 pub trait BlockHandler {
-    async fn handle(&self, params: BlockContext);
+    async fn handle(&self, params: BlockContext) -> HandlerResult;
     fn name(&self) -> String;
 }
 
@@ -38,49 +52,99 @@ pub struct ProcessBlocksInput {
     pub handler: BlockHandlerInstance,
     pub templates: TemplateManager,
     pub provider: Provider,
+    pub store: Store,
+    /// Latest block to index up to, instead of the latest finalized block,
+    /// after which the source stops.
+    pub head: Option<u64>,
     pub config: BlockHandlerConfig,
 }
 
 pub async fn process_blocks(
-    ProcessBlocksInput { handler, templates, provider, config }: ProcessBlocksInput,
+    ProcessBlocksInput { handler, templates, provider, store, head, config }: ProcessBlocksInput,
 ) -> Result<(), TransportError> {
     let execution_mode = config.execution_mode.unwrap_or(ExecutionMode::Parallel);
+    let checkpoint = handler.name();
+
+    let mut current_block = match store.checkpoint(&checkpoint).await {
+        Ok(Some(block_number)) => config.start_block.max(block_number + config.step),
+        Ok(None) => config.start_block,
+        Err(error) => return Err(TransportError::local_usage(error)),
+    };
 
-    let mut current_block = config.start_block;
     let mut latest_block_manager =
-        LatestBlockManager::new(provider.clone(), Duration::from_secs(10));
+        LatestBlockManager::new(provider.clone(), Duration::from_secs(10), head);
+
+    let concurrency = ConcurrencyConfig {
+        max_pending_ranges: Some(
+            config.concurrency.max_pending_ranges.unwrap_or(DEFAULT_BLOCK_PENDING_RANGES),
+        ),
+        ..config.concurrency.clone()
+    };
+
+    let mut pending_ranges = PendingRanges::new(checkpoint, &concurrency);
 
     loop {
         let latest_block = latest_block_manager.get().await?;
 
         if current_block >= latest_block {
+            pending_ranges.flush().await?;
+
+            // Running from the cache, there are no blocks past the recorded head
+            if head.is_some() {
+                return Ok(());
+            }
+
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
             continue;
         }
 
+        let store_transaction = pending_ranges
+            .transaction(&store)
+            .with_metadata(Metadata { block_number: current_block, ..Default::default() });
+
+        let templates = templates.with_store(store_transaction.clone());
+
         match execution_mode {
+            // Each block is a range, so at most `max_pending_ranges + 1` blocks are handled at once,
+            // 33 by default
             ExecutionMode::Parallel => {
                 let handler = handler.clone();
                 let provider = provider.clone();
-                let templates = templates.clone();
+                let store_transaction = store_transaction.clone();
 
-                tokio::spawn(async move {
-                    handler
-                        .handle(BlockContext { provider, templates, block_number: current_block })
-                        .await;
-                });
+                pending_ranges
+                    .spawn(async move {
+                        handler
+                            .handle(BlockContext {
+                                provider,
+                                templates,
+                                store: store_transaction,
+                                block_number: current_block,
+                            })
+                            .await
+                    })
+                    .await?;
             }
-            ExecutionMode::Serial => {
-                let templates = templates.clone();
+            // Blocks have no key, so they are handled in order
+            ExecutionMode::Serial | ExecutionMode::Keyed => {
                 let provider = provider.clone();
-                let templates = templates.clone();
+                let store = store_transaction.clone();
 
-                handler
-                    .handle(BlockContext { provider, templates, block_number: current_block })
+                let result = handler
+                    .handle(BlockContext {
+                        provider,
+                        templates,
+                        store,
+                        block_number: current_block,
+                    })
                     .await;
+
+                pending_ranges.tolerate_miss(result.map_err(TransportError::LocalUsageError))?;
             }
         }
 
+        pending_ranges.push(store_transaction, current_block).await?;
+
         current_block += config.step;
     }
 }
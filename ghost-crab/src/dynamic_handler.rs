@@ -0,0 +1,256 @@
+use crate::event_handler::{EventContext, EventHandler, HandlerResult};
+use crate::indexer::error::{Error, Result};
+use crate::indexer::rpc_manager::Provider;
+use crate::indexer::templates::TemplateManager;
+use crate::store::StoreTransaction;
+use alloy::dyn_abi::{DecodedEvent, DynSolEvent, DynSolValue, Specifier};
+use alloy::json_abi::{ContractObject, Event, JsonAbi};
+use alloy::primitives::Address;
+use alloy::rpc::types::eth::Log;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Context of a dynamic handler, with the log decoded with the ABI loaded at
+/// runtime.
+pub struct DynamicEventContext {
+    pub log: Log,
+    /// Name of the data source of the log.
+    pub source: String,
+    /// Event of the ABI the log was decoded with.
+    pub event: Arc<Event>,
+    pub decoded: DecodedEvent,
+    pub provider: Provider,
+    pub templates: TemplateManager,
+    pub store: StoreTransaction,
+    pub contract_address: Address,
+}
+
+impl DynamicEventContext {
+    /// Returns the parameters of the event with their values, in the order of
+    /// the ABI, whether they are indexed or not.
+    pub fn params(&self) -> Vec<(&str, &DynSolValue)> {
+        let mut indexed = self.decoded.indexed.iter();
+        let mut body = self.decoded.body.iter();
+
+        self.event
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                let value = match input.indexed {
+                    true => indexed.next(),
+                    false => body.next(),
+                };
+
+                value.map(|value| (input.name.as_str(), value))
+            })
+            .collect()
+    }
+
+    /// Returns the value of a parameter of the event by name.
+    pub fn param(&self, name: &str) -> Option<&DynSolValue> {
+        self.params()
+            .into_iter()
+            .find(|(param_name, _)| *param_name == name)
+            .map(|(_, value)| value)
+    }
+}
+
+pub type DynamicEventHandlerInstance = Arc<Box<dyn DynamicEventHandler + Send + Sync>>;
+
+/// Handler of logs decoded at runtime, which can handle the events of any
+/// data source without being generated by the macros.
+#[async_trait]
+pub trait DynamicEventHandler {
+    async fn handle(&self, params: DynamicEventContext) -> HandlerResult;
+}
+
+/// Loads the ABI of a contract from a JSON file, either the ABI itself or an
+/// artifact with an `abi` field.
+pub fn load_abi(path: &str) -> Result<JsonAbi> {
+    let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+    let contract: ContractObject = serde_json::from_str(&contents).map_err(Error::InvalidAbi)?;
+
+    Ok(contract.abi.unwrap_or_default())
+}
+
+/// Returns the event of the ABI with the name, or with the signature (e.g.
+/// `Transfer(address,address,uint256)`) when the name is overloaded.
+pub fn find_event(abi: &JsonAbi, name: &str) -> Result<Event> {
+    if name.contains('(') {
+        return abi
+            .events()
+            .find(|event| event.signature() == name)
+            .cloned()
+            .ok_or(Error::EventNotFound(name.to_string()));
+    }
+
+    match abi.event(name).map(Vec::as_slice) {
+        Some([event]) => Ok(event.clone()),
+        Some(events) if !events.is_empty() => {
+            let signatures = events.iter().map(Event::signature).collect::<Vec<_>>();
+            Err(Error::AmbiguousEvent(name.to_string(), signatures))
+        }
+        _ => Err(Error::EventNotFound(name.to_string())),
+    }
+}
+
+/// Event handler decoding the logs of an event with its ABI before passing
+/// them to a dynamic handler. It can be started as a template, named after it:
+///
+/// ```ignore
+/// let abi = dynamic_handler::load_abi("abis/ETHVault.json")?;
+/// let event = dynamic_handler::find_event(&abi, "Deposited")?;
+/// let handler: EventHandlerInstance = Arc::new(Box::new(DynamicEvent::new("ETHVault", event, ingest)?));
+///
+/// ctx.templates.start(Template { address, start_block, handler }).await?;
+/// ```
+pub struct DynamicEvent {
+    source: String,
+    event: Arc<Event>,
+    resolved: DynSolEvent,
+    handler: DynamicEventHandlerInstance,
+}
+
+impl DynamicEvent {
+    /// Creates the handler of an event of the data source or template named `source`.
+    pub fn new(
+        source: &str,
+        event: Event,
+        handler: DynamicEventHandlerInstance,
+    ) -> Result<DynamicEvent> {
+        let resolved = event.resolve().map_err(Error::InvalidEvent)?;
+
+        Ok(DynamicEvent { source: source.to_string(), event: Arc::new(event), resolved, handler })
+    }
+}
+
+#[async_trait]
+impl EventHandler for DynamicEvent {
+    async fn handle(&self, ctx: EventContext) -> HandlerResult {
+        let decoded = match self.resolved.decode_log_parts(
+            ctx.log.topics().iter().copied(),
+            &ctx.log.data().data,
+            true,
+        ) {
+            Ok(decoded) => decoded,
+            // A log matching the signature with other indexed fields can't be decoded, it is skipped
+            Err(error) => {
+                eprintln!(
+                    "[{}] Skipping a log of transaction {}, error decoding its data: {}",
+                    self.source,
+                    ctx.log.transaction_hash.unwrap_or_default(),
+                    error
+                );
+
+                return Ok(());
+            }
+        };
+
+        self.handler
+            .handle(DynamicEventContext {
+                log: ctx.log,
+                source: self.source.clone(),
+                event: Arc::clone(&self.event),
+                decoded,
+                provider: ctx.provider,
+                templates: ctx.templates,
+                store: ctx.store,
+                contract_address: ctx.contract_address,
+            })
+            .await
+    }
+
+    fn name(&self) -> String {
+        self.source.clone()
+    }
+
+    fn event_signature(&self) -> String {
+        self.event.signature()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestHarness;
+    use alloy::primitives::{address, LogData, B256};
+    use std::sync::Mutex;
+
+    const ABI: &str = r#"[
+        { "type": "event", "name": "Transfer", "anonymous": false, "inputs": [
+            { "name": "from", "type": "address", "indexed": true },
+            { "name": "to", "type": "address", "indexed": true },
+            { "name": "value", "type": "uint256", "indexed": false }
+        ] },
+        { "type": "event", "name": "Transfer", "anonymous": false, "inputs": [
+            { "name": "from", "type": "address", "indexed": true },
+            { "name": "to", "type": "address", "indexed": true },
+            { "name": "value", "type": "uint256", "indexed": false },
+            { "name": "data", "type": "bytes", "indexed": false }
+        ] },
+        { "type": "event", "name": "Paused", "anonymous": false, "inputs": [
+            { "name": "account", "type": "address", "indexed": false }
+        ] }
+    ]"#;
+
+    fn abi() -> JsonAbi {
+        serde_json::from_str(ABI).unwrap()
+    }
+
+    #[test]
+    fn finds_the_event_by_name_or_signature() {
+        let abi = abi();
+
+        assert_eq!(find_event(&abi, "Paused").unwrap().signature(), "Paused(address)");
+
+        let signature = "Transfer(address,address,uint256,bytes)";
+        assert_eq!(find_event(&abi, signature).unwrap().signature(), signature);
+
+        assert!(
+            matches!(find_event(&abi, "Transfer"), Err(Error::AmbiguousEvent(_, signatures)) if signatures.len() == 2)
+        );
+        assert!(matches!(find_event(&abi, "Unpaused"), Err(Error::EventNotFound(_))));
+        assert!(matches!(find_event(&abi, "Paused(uint256)"), Err(Error::EventNotFound(_))));
+    }
+
+    /// Records the accounts of the decoded logs.
+    struct Recorder(Arc<Mutex<Vec<DynSolValue>>>);
+
+    #[async_trait]
+    impl DynamicEventHandler for Recorder {
+        async fn handle(&self, ctx: DynamicEventContext) -> HandlerResult {
+            self.0.lock().unwrap().push(ctx.param("account").cloned().unwrap());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_the_logs_that_cant_be_decoded() {
+        let contract = address!("0000000000000000000000000000000000000001");
+        let account = address!("0000000000000000000000000000000000000002");
+
+        let event = find_event(&abi(), "Paused").unwrap();
+        let topics = vec![event.selector()];
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let handler =
+            DynamicEvent::new("Token", event, Arc::new(Box::new(Recorder(recorded.clone()))))
+                .unwrap();
+
+        let harness = TestHarness::new();
+
+        for data in [account.into_word().to_vec(), Vec::new()] {
+            let log = Log {
+                inner: alloy::primitives::Log {
+                    address: contract,
+                    data: LogData::new_unchecked(topics.clone(), data.into()),
+                },
+                transaction_hash: Some(B256::ZERO),
+                ..Default::default()
+            };
+
+            handler.handle(harness.event_context(log, contract)).await.unwrap();
+        }
+
+        assert_eq!(*recorded.lock().unwrap(), vec![DynSolValue::Address(account)]);
+    }
+}
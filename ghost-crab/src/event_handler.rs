@@ -1,15 +1,19 @@
 use crate::indexer::rpc_manager::Provider;
 use crate::indexer::templates::TemplateManager;
 use crate::latest_block_manager::LatestBlockManager;
-use alloy::eips::BlockNumberOrTag;
-use alloy::primitives::Address;
+use crate::multicall::Multicall;
+use crate::pending_ranges::PendingRanges;
+use crate::store::{Metadata, Store, StoreTransaction};
+use alloy::eips::{BlockId, BlockNumberOrTag};
+use alloy::primitives::{Address, B256};
 use alloy::providers::Provider as AlloyProvider;
 use alloy::rpc::types::eth::Filter;
 use alloy::rpc::types::eth::Log;
 use alloy::rpc::types::Block;
 use alloy::transports::TransportError;
 use async_trait::async_trait;
-use ghost_crab_common::config::ExecutionMode;
+use ghost_crab_common::config::{ConcurrencyConfig, ExecutionMode};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -17,6 +21,7 @@ pub struct EventContext {
     pub log: Log,
     pub provider: Provider,
     pub templates: TemplateManager,
+    pub store: StoreTransaction,
     pub contract_address: Address,
 }
 
@@ -31,15 +36,121 @@ impl EventContext {
             None => Err(TransportError::local_usage_str("Error occurred while fetching the current block number within an EventHandler. The log.block_number value is None.")),
         }
     }
+
+    /// Returns a helper that aggregates the `eth_call`s made concurrently at
+    /// the block of the log into a single Multicall3 call.
+    pub fn multicall(&self) -> Multicall {
+        let block = self.log.block_number.map(BlockId::number).unwrap_or(BlockId::latest());
+        Multicall::new(self.provider.clone(), block)
+    }
+}
+
+/// Context of a batch handler, with all the logs of a block range.
+pub struct BatchEventContext {
+    pub logs: Vec<Log>,
+    pub from_block: u64,
+    pub to_block: u64,
+    pub provider: Provider,
+    pub templates: TemplateManager,
+    /// Transaction of the range, whose writes are tagged with its last block.
+    pub store: StoreTransaction,
+    pub contract_address: Address,
+}
+
+impl BatchEventContext {
+    /// Returns a handle to the transaction of the range whose writes are
+    /// tagged with the block, index and transaction of the log.
+    pub fn store_for<T>(&self, log: &Log<T>) -> StoreTransaction {
+        self.store.with_metadata(log_metadata(log))
+    }
+
+    /// Returns a helper that aggregates the `eth_call`s made concurrently at
+    /// the last block of the range into a single Multicall3 call.
+    pub fn multicall(&self) -> Multicall {
+        Multicall::new(self.provider.clone(), BlockId::number(self.to_block))
+    }
+}
+
+impl From<EventContext> for BatchEventContext {
+    /// Returns a batch with the single log, in a range of its block.
+    fn from(ctx: EventContext) -> Self {
+        let block_number = ctx.log.block_number.unwrap_or_default();
+
+        BatchEventContext {
+            logs: vec![ctx.log],
+            from_block: block_number,
+            to_block: block_number,
+            provider: ctx.provider,
+            templates: ctx.templates,
+            store: ctx.store,
+            contract_address: ctx.contract_address,
+        }
+    }
 }
 
 pub type EventHandlerInstance = Arc<Box<(dyn EventHandler + Send + Sync)>>;
 
+/// Error returned by a handler, e.g. when a write to the store fails. The
+/// range of the handler is then not committed, and the source stops.
+pub type HandlerError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type HandlerResult = Result<(), HandlerError>;
+
+/// Converts what the functions given to the macros return into a
+/// [`HandlerResult`], so they can return nothing or a `Result`.
+pub trait IntoHandlerResult {
+    fn into_handler_result(self) -> HandlerResult;
+}
+
+impl IntoHandlerResult for () {
+    fn into_handler_result(self) -> HandlerResult {
+        Ok(())
+    }
+}
+
+impl<E: Into<HandlerError>> IntoHandlerResult for Result<(), E> {
+    fn into_handler_result(self) -> HandlerResult {
+        self.map_err(Into::into)
+    }
+}
+
 #[async_trait]
 pub trait EventHandler {
-    async fn handle(&self, params: EventContext);
+    async fn handle(&self, params: EventContext) -> HandlerResult;
     fn name(&self) -> String;
     fn event_signature(&self) -> String;
+
+    /// Returns the key of the log in `Keyed` mode, where the logs with the
+    /// same key are handled in order. Defaults to the address of the contract,
+    /// so the logs of a source are handled in order like in `Serial` mode.
+    fn key(&self, log: &Log) -> B256 {
+        log.address().into_word()
+    }
+
+    /// Returns whether the handler receives all the logs of a range at once
+    /// through `handle_batch`, instead of one by one through `handle`.
+    fn batch(&self) -> bool {
+        false
+    }
+
+    /// Handles all the logs of a range at once. Defaults to handling them one
+    /// by one.
+    async fn handle_batch(&self, params: BatchEventContext) -> HandlerResult {
+        for log in params.logs {
+            let store = params.store.with_metadata(log_metadata(&log));
+
+            self.handle(EventContext {
+                log,
+                provider: params.provider.clone(),
+                templates: params.templates.clone(),
+                store,
+                contract_address: params.contract_address,
+            })
+            .await?;
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -50,17 +161,57 @@ pub struct ProcessEventsInput {
     pub handler: EventHandlerInstance,
     pub templates: TemplateManager,
     pub provider: Provider,
+    pub store: Store,
+    /// Latest block to index up to, instead of the latest finalized block,
+    /// after which the source stops.
+    pub head: Option<u64>,
     pub execution_mode: ExecutionMode,
+    pub concurrency: ConcurrencyConfig,
+}
+
+fn log_metadata<T>(log: &Log<T>) -> Metadata {
+    Metadata {
+        block_number: log.block_number.unwrap_or_default(),
+        log_index: log.log_index,
+        tx_hash: log.transaction_hash,
+    }
 }
 
 pub async fn process_events(
-    ProcessEventsInput { start_block, execution_mode, step, address, handler, templates, provider }: ProcessEventsInput,
+    ProcessEventsInput {
+        start_block,
+        execution_mode,
+        step,
+        address,
+        handler,
+        templates,
+        provider,
+        store,
+        head,
+        concurrency,
+    }: ProcessEventsInput,
 ) -> Result<(), TransportError> {
     let event_signature = handler.event_signature();
+    // Several events of a source can be handled, each with its own checkpoint
+    let checkpoint = format!("{}:{}:{}", handler.name(), event_signature, address);
+
+    // The checkpoints were keyed by source before, when a source had a single event
+    let legacy_checkpoint = format!("{}:{}", handler.name(), address);
+
+    let saved_checkpoint = match store.checkpoint(&checkpoint).await {
+        Ok(None) => store.checkpoint(&legacy_checkpoint).await,
+        result => result,
+    };
+
+    let mut current_block = match saved_checkpoint {
+        Ok(Some(block_number)) => start_block.max(block_number + 1),
+        Ok(None) => start_block,
+        Err(error) => return Err(TransportError::local_usage(error)),
+    };
 
-    let mut current_block = start_block;
     let mut latest_block_manager =
-        LatestBlockManager::new(provider.clone(), Duration::from_secs(10));
+        LatestBlockManager::new(provider.clone(), Duration::from_secs(10), head);
+    let mut pending_ranges = PendingRanges::new(checkpoint, &concurrency);
 
     loop {
         let mut end_block = current_block + step;
@@ -70,7 +221,14 @@ pub async fn process_events(
             end_block = latest_block;
         }
 
-        if current_block >= end_block {
+        if current_block > end_block {
+            pending_ranges.flush().await?;
+
+            // Running from the cache, there are no blocks past the recorded head
+            if head.is_some() {
+                return Ok(());
+            }
+
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
             continue;
         }
@@ -85,7 +243,46 @@ pub async fn process_events(
             .from_block(current_block)
             .to_block(end_block);
 
-        let logs = provider.get_logs(&filter).await?;
+        let logs = pending_ranges.tolerate_miss(provider.get_logs(&filter).await)?;
+        let logs = logs.unwrap_or_default();
+        let store_transaction = pending_ranges.transaction(&store);
+        let templates = templates.with_store(store_transaction.clone());
+
+        if handler.batch() {
+            if !logs.is_empty() {
+                let handler = handler.clone();
+
+                let ctx = BatchEventContext {
+                    logs,
+                    from_block: current_block,
+                    to_block: end_block,
+                    provider: provider.clone(),
+                    templates,
+                    store: store_transaction
+                        .with_metadata(Metadata { block_number: end_block, ..Default::default() }),
+                    contract_address: address,
+                };
+
+                match execution_mode {
+                    ExecutionMode::Parallel => {
+                        pending_ranges
+                            .spawn(async move { handler.handle_batch(ctx).await })
+                            .await?;
+                    }
+                    // A range is a single batch, so there are no keys to run concurrently
+                    ExecutionMode::Serial | ExecutionMode::Keyed => {
+                        let result = handler.handle_batch(ctx).await;
+                        pending_ranges
+                            .tolerate_miss(result.map_err(TransportError::LocalUsageError))?;
+                    }
+                }
+            }
+
+            pending_ranges.push(store_transaction, end_block).await?;
+            current_block = end_block + 1;
+
+            continue;
+        }
 
         match execution_mode {
             ExecutionMode::Parallel => {
@@ -93,36 +290,348 @@ pub async fn process_events(
                     let handler = handler.clone();
                     let provider = provider.clone();
                     let templates = templates.clone();
+                    let store = store_transaction.with_metadata(log_metadata(&log));
 
-                    tokio::spawn(async move {
-                        handler
-                            .handle(EventContext {
-                                log,
-                                provider,
-                                templates,
-                                contract_address: address,
-                            })
-                            .await;
-                    });
+                    pending_ranges
+                        .spawn(async move {
+                            handler
+                                .handle(EventContext {
+                                    log,
+                                    provider,
+                                    templates,
+                                    store,
+                                    contract_address: address,
+                                })
+                                .await
+                        })
+                        .await?;
                 }
             }
             ExecutionMode::Serial => {
                 for log in logs {
                     let templates = templates.clone();
                     let provider = provider.clone();
+                    let store = store_transaction.with_metadata(log_metadata(&log));
 
-                    handler
+                    // The range is not committed, so it is handled again after a restart
+                    let result = handler
                         .handle(EventContext {
                             log,
                             provider,
                             templates,
+                            store,
                             contract_address: address,
                         })
                         .await;
+
+                    pending_ranges
+                        .tolerate_miss(result.map_err(TransportError::LocalUsageError))?;
+                }
+            }
+            ExecutionMode::Keyed => {
+                let mut queues: Vec<(B256, Vec<Log>)> = Vec::new();
+                let mut queue_indexes: HashMap<B256, usize> = HashMap::new();
+
+                for log in logs {
+                    let key = handler.key(&log);
+
+                    let index = *queue_indexes.entry(key).or_insert_with(|| {
+                        queues.push((key, Vec::new()));
+                        queues.len() - 1
+                    });
+
+                    queues[index].1.push(log);
+                }
+
+                for (key, logs) in queues {
+                    let handler = handler.clone();
+                    let provider = provider.clone();
+                    let templates = templates.clone();
+                    let store_transaction = store_transaction.clone();
+
+                    pending_ranges
+                        .spawn_keyed(key, async move {
+                            for log in logs {
+                                let store = store_transaction.with_metadata(log_metadata(&log));
+
+                                handler
+                                    .handle(EventContext {
+                                        log,
+                                        provider: provider.clone(),
+                                        templates: templates.clone(),
+                                        store,
+                                        contract_address: address,
+                                    })
+                                    .await?;
+                            }
+
+                            Ok(())
+                        })
+                        .await?;
                 }
             }
         }
 
-        current_block = end_block;
+        pending_ranges.push(store_transaction, end_block).await?;
+
+        current_block = end_block + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::cache_layer::CacheMiss;
+    use crate::testing::{self, MockTransport};
+    use alloy::primitives::address;
+    use alloy::sol;
+    use alloy::sol_types::SolEvent;
+    use alloy::transports::TransportErrorKind;
+    use serde_json::json;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    sol! {
+        event Transfer(address indexed from, address indexed to, uint256 value);
+    }
+
+    /// Records the block number of every handled log.
+    struct Recorder {
+        blocks: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[async_trait]
+    impl EventHandler for Recorder {
+        async fn handle(&self, ctx: EventContext) -> HandlerResult {
+            self.blocks.lock().unwrap().push(ctx.log.block_number.unwrap_or_default());
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "Token".to_string()
+        }
+
+        fn event_signature(&self) -> String {
+            Transfer::SIGNATURE.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_head() {
+        let token = address!("0000000000000000000000000000000000000001");
+        let transfer = Transfer { from: Address::ZERO, to: token, value: Default::default() };
+
+        let transport = MockTransport::new();
+        transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 5)]));
+        transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 15)]));
+
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let (tx, _rx) = mpsc::channel(1);
+
+        let result = process_events(ProcessEventsInput {
+            start_block: 0,
+            address: token,
+            step: 10,
+            handler: Arc::new(Box::new(Recorder { blocks: blocks.clone() })),
+            templates: TemplateManager::new(tx),
+            provider: transport.provider(),
+            store: Store::default(),
+            head: Some(15),
+            execution_mode: ExecutionMode::Serial,
+            concurrency: ConcurrencyConfig::default(),
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(*blocks.lock().unwrap(), [5, 15]);
+        assert_eq!(transport.requests().len(), 2);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn resumes_from_the_checkpoint_of_the_source() {
+        use crate::store::{sqlite::SqliteStore, StoreBackend};
+
+        let token = address!("0000000000000000000000000000000000000001");
+        let transfer = Transfer { from: Address::ZERO, to: token, value: Default::default() };
+
+        let backend = SqliteStore::connect("sqlite::memory:").unwrap();
+        backend.migrate().await.unwrap();
+        let store = Store::new(Arc::new(backend));
+
+        // Checkpoint saved before the checkpoints were keyed by event
+        store.transaction().commit(&format!("Token:{token}"), 9).await.unwrap();
+
+        let transport = MockTransport::new();
+        transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 15)]));
+
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let (tx, _rx) = mpsc::channel(1);
+
+        let result = process_events(ProcessEventsInput {
+            start_block: 0,
+            address: token,
+            step: 10,
+            handler: Arc::new(Box::new(Recorder { blocks: blocks.clone() })),
+            templates: TemplateManager::new(tx),
+            provider: transport.provider(),
+            store: store.clone(),
+            head: Some(15),
+            execution_mode: ExecutionMode::Serial,
+            concurrency: ConcurrencyConfig::default(),
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(*blocks.lock().unwrap(), [15]);
+        assert_eq!(transport.requests()[0].1[0]["fromBlock"], json!("0xa"));
+
+        let checkpoint = format!("Token:{}:{token}", Transfer::SIGNATURE);
+        assert_eq!(store.checkpoint(&checkpoint).await.unwrap(), Some(15));
+    }
+
+    /// Fails on the first log as if a request was missing from the cache.
+    struct Offline {
+        blocks: Arc<Mutex<Vec<u64>>>,
+    }
+
+    #[async_trait]
+    impl EventHandler for Offline {
+        async fn handle(&self, ctx: EventContext) -> HandlerResult {
+            let block_number = ctx.log.block_number.unwrap_or_default();
+            self.blocks.lock().unwrap().push(block_number);
+
+            if block_number == 5 {
+                let miss = CacheMiss { method: "eth_call".to_string(), params: json!([]) };
+                return Err(Box::new(TransportErrorKind::custom(miss)));
+            }
+
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "Token".to_string()
+        }
+
+        fn event_signature(&self) -> String {
+            Transfer::SIGNATURE.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_running_past_cache_misses() {
+        let token = address!("0000000000000000000000000000000000000001");
+        let transfer = Transfer { from: Address::ZERO, to: token, value: Default::default() };
+
+        let transport = MockTransport::new();
+
+        for execution_mode in [ExecutionMode::Serial, ExecutionMode::Parallel] {
+            transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 5)]));
+            transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 15)]));
+
+            let blocks = Arc::new(Mutex::new(Vec::new()));
+            let (tx, _rx) = mpsc::channel(1);
+
+            let result = process_events(ProcessEventsInput {
+                start_block: 0,
+                address: token,
+                step: 10,
+                handler: Arc::new(Box::new(Offline { blocks: blocks.clone() })),
+                templates: TemplateManager::new(tx),
+                provider: transport.provider(),
+                store: Store::default(),
+                head: Some(15),
+                execution_mode,
+                concurrency: ConcurrencyConfig::default(),
+            })
+            .await;
+
+            assert!(result.is_ok());
+            assert_eq!(*blocks.lock().unwrap(), [5, 15]);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    /// Range and blocks of the logs of a batch.
+    type Batch = (u64, u64, Vec<u64>);
+
+    #[cfg(feature = "sqlite")]
+    /// Records the range and the blocks of the logs of every batch.
+    struct Batcher {
+        batches: Arc<Mutex<Vec<Batch>>>,
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[async_trait]
+    impl EventHandler for Batcher {
+        async fn handle(&self, _ctx: EventContext) -> HandlerResult {
+            panic!("The logs of a batch handler are handled by handle_batch");
+        }
+
+        fn name(&self) -> String {
+            "Token".to_string()
+        }
+
+        fn event_signature(&self) -> String {
+            Transfer::SIGNATURE.to_string()
+        }
+
+        fn batch(&self) -> bool {
+            true
+        }
+
+        async fn handle_batch(&self, ctx: BatchEventContext) -> HandlerResult {
+            let blocks = ctx.logs.iter().map(|log| log.block_number.unwrap_or_default()).collect();
+            self.batches.lock().unwrap().push((ctx.from_block, ctx.to_block, blocks));
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn handles_the_logs_of_each_range_in_a_batch() {
+        use crate::store::{sqlite::SqliteStore, StoreBackend};
+
+        let token = address!("0000000000000000000000000000000000000001");
+        let transfer = Transfer { from: Address::ZERO, to: token, value: Default::default() };
+        let checkpoint = format!("Token:{}:{token}", Transfer::SIGNATURE);
+
+        for execution_mode in [ExecutionMode::Serial, ExecutionMode::Parallel] {
+            let backend = SqliteStore::connect("sqlite::memory:").unwrap();
+            backend.migrate().await.unwrap();
+            let store = Store::new(Arc::new(backend));
+
+            let transport = MockTransport::new();
+            transport.push_response(
+                "eth_getLogs",
+                json!([testing::log(token, &transfer, 5), testing::log(token, &transfer, 7)]),
+            );
+            transport.push_response("eth_getLogs", json!([testing::log(token, &transfer, 15)]));
+
+            let batches = Arc::new(Mutex::new(Vec::new()));
+            let (tx, _rx) = mpsc::channel(1);
+
+            let result = process_events(ProcessEventsInput {
+                start_block: 0,
+                address: token,
+                step: 10,
+                handler: Arc::new(Box::new(Batcher { batches: batches.clone() })),
+                templates: TemplateManager::new(tx),
+                provider: transport.provider(),
+                store: store.clone(),
+                head: Some(15),
+                execution_mode,
+                concurrency: ConcurrencyConfig::default(),
+            })
+            .await;
+
+            assert!(result.is_ok());
+
+            let mut batches = batches.lock().unwrap().clone();
+            batches.sort();
+            assert_eq!(batches, [(0, 10, vec![5, 7]), (11, 15, vec![15])]);
+
+            assert_eq!(store.checkpoint(&checkpoint).await.unwrap(), Some(15));
+        }
     }
 }
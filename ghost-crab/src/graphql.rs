@@ -0,0 +1,504 @@
+use crate::store::{Filter, OrderBy, OrderDirection, Query, Row, Store};
+use alloy::primitives::{I256, U256};
+use async_graphql::dynamic::{
+    Enum, Field, FieldFuture, FieldValue, InputObject, InputValue, Object, ResolverContext, Scalar,
+    Schema, SchemaError, TypeRef, ValueAccessor,
+};
+use async_graphql::{ServerError, Value as GraphqlValue};
+use bytes::Bytes;
+use ghost_crab_common::schema::{FieldType, Schema as EntitySchema};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use std::str::FromStr;
+use tokio::net::TcpListener;
+
+const BIG_INT: &str = "BigInt";
+const DEFAULT_FIRST: u64 = 100;
+const MAX_FIRST: u64 = 1000;
+
+#[derive(Clone)]
+struct EntityType {
+    name: String,
+    fields: Vec<(String, FieldType)>,
+}
+
+fn to_graphql_value(field_type: FieldType, value: &Value) -> Option<GraphqlValue> {
+    let value = match (field_type, value) {
+        (_, Value::Null) => return None,
+        (FieldType::Bool, Value::Bool(value)) => GraphqlValue::from(*value),
+        (FieldType::Uint256, value) => {
+            let value = serde_json::from_value::<U256>(value.clone()).ok()?;
+            GraphqlValue::from(value.to_string())
+        }
+        (FieldType::Int256, value) => {
+            let value = serde_json::from_value::<I256>(value.clone()).ok()?;
+            GraphqlValue::from(value.to_string())
+        }
+        (_, Value::String(value)) => GraphqlValue::from(value.clone()),
+        (_, value) => GraphqlValue::from(value.to_string()),
+    };
+
+    Some(value)
+}
+
+fn to_json_value(field_type: FieldType, value: &ValueAccessor) -> async_graphql::Result<Value> {
+    if field_type == FieldType::Bool {
+        return Ok(Value::Bool(value.boolean()?));
+    }
+
+    // BigInt values can be sent either as strings or as numbers
+    let value = match value.as_value() {
+        GraphqlValue::Number(number) => number.to_string(),
+        _ => value.string()?.to_string(),
+    };
+
+    let value = match field_type {
+        FieldType::Uint64 => Value::from(u64::from_str(&value)?),
+        FieldType::Int64 => Value::from(i64::from_str(&value)?),
+        FieldType::Uint256 => serde_json::to_value(U256::from_str(&value)?)?,
+        FieldType::Int256 => serde_json::to_value(I256::from_dec_str(&value)?)?,
+        _ => Value::String(value),
+    };
+
+    Ok(value)
+}
+
+fn graphql_type(field_type: FieldType) -> &'static str {
+    match field_type {
+        FieldType::Bool => TypeRef::BOOLEAN,
+        FieldType::Uint64 | FieldType::Int64 | FieldType::Uint256 | FieldType::Int256 => BIG_INT,
+        _ => TypeRef::STRING,
+    }
+}
+
+fn lowercase_first(name: &str) -> String {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn entity_object(entity: &EntityType) -> Object {
+    let object = Object::new(&entity.name)
+        .field(Field::new("id", TypeRef::named_nn(TypeRef::ID), |ctx| {
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                Ok(Some(GraphqlValue::from(row.id.clone())))
+            })
+        }))
+        .field(Field::new("block_number", TypeRef::named_nn(BIG_INT), |ctx| {
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                Ok(Some(GraphqlValue::from(row.metadata.block_number.to_string())))
+            })
+        }))
+        .field(Field::new("log_index", TypeRef::named(BIG_INT), |ctx| {
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                Ok(row
+                    .metadata
+                    .log_index
+                    .map(|log_index| GraphqlValue::from(log_index.to_string())))
+            })
+        }))
+        .field(Field::new("tx_hash", TypeRef::named(TypeRef::STRING), |ctx| {
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                Ok(row.metadata.tx_hash.map(|tx_hash| GraphqlValue::from(tx_hash.to_string())))
+            })
+        }));
+
+    entity.fields.iter().fold(object, |object, (field_name, field_type)| {
+        let field_type = *field_type;
+        let name = field_name.clone();
+
+        object.field(Field::new(field_name, TypeRef::named(graphql_type(field_type)), move |ctx| {
+            let name = name.clone();
+
+            FieldFuture::new(async move {
+                let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                let value = row.data.get(&name).unwrap_or(&Value::Null);
+                Ok(to_graphql_value(field_type, value))
+            })
+        }))
+    })
+}
+
+fn entity_filter(entity: &EntityType) -> InputObject {
+    let filter = InputObject::new(format!("{}_filter", entity.name))
+        .field(InputValue::new("id", TypeRef::named(TypeRef::ID)))
+        .field(InputValue::new("block_number", TypeRef::named(BIG_INT)));
+
+    entity.fields.iter().fold(filter, |filter, (field_name, field_type)| {
+        filter.field(InputValue::new(field_name, TypeRef::named(graphql_type(*field_type))))
+    })
+}
+
+/// The `int256` fields are stored in two's complement hex, which can't be ordered in SQL.
+fn is_orderable(field_type: FieldType) -> bool {
+    field_type != FieldType::Int256
+}
+
+fn entity_order_by(entity: &EntityType) -> Enum {
+    let order_by = Enum::new(format!("{}_orderBy", entity.name)).item("id").item("block_number");
+
+    entity
+        .fields
+        .iter()
+        .filter(|(_, field_type)| is_orderable(*field_type))
+        .fold(order_by, |order_by, (field_name, _)| order_by.item(field_name))
+}
+
+fn field_type(entity: &EntityType, name: &str) -> async_graphql::Result<FieldType> {
+    entity
+        .fields
+        .iter()
+        .find(|(field_name, _)| field_name == name)
+        .map(|(_, field_type)| *field_type)
+        .ok_or_else(|| format!("Unknown field: {}", name).into())
+}
+
+fn get_query(entity: &EntityType, ctx: &ResolverContext) -> async_graphql::Result<Query> {
+    let mut filters = Vec::new();
+
+    if let Some(filter) = ctx.args.get("where") {
+        for (name, value) in filter.object()?.iter() {
+            if value.is_null() {
+                continue;
+            }
+
+            let filter = match name.as_str() {
+                "id" => Filter::Id(value.string()?.to_string()),
+                "block_number" => Filter::BlockNumber(
+                    to_json_value(FieldType::Uint64, &value)?.as_u64().unwrap_or_default(),
+                ),
+                name => {
+                    let field_type = field_type(entity, name)?;
+                    let value = to_json_value(field_type, &value)?;
+
+                    // Hex values can be sent with any case
+                    match field_type {
+                        FieldType::Address | FieldType::Bytes | FieldType::Bytes32 => {
+                            Filter::FieldIgnoreCase(name.to_string(), value)
+                        }
+                        _ => Filter::Field(name.to_string(), value),
+                    }
+                }
+            };
+
+            filters.push(filter);
+        }
+    }
+
+    let order_by = match ctx.args.get("orderBy") {
+        Some(order_by) => match order_by.enum_name()? {
+            "id" => OrderBy::Id,
+            "block_number" => OrderBy::BlockNumber,
+            field_name => match field_type(entity, field_name)? {
+                FieldType::Uint256 => OrderBy::Uint256(field_name.to_string()),
+                _ => OrderBy::Field(field_name.to_string()),
+            },
+        },
+        None => OrderBy::Id,
+    };
+
+    let order_direction = match ctx.args.get("orderDirection") {
+        Some(order_direction) if order_direction.enum_name()? == "desc" => OrderDirection::Desc,
+        _ => OrderDirection::Asc,
+    };
+
+    let first = match ctx.args.get("first") {
+        Some(first) => first.u64()?.min(MAX_FIRST),
+        None => DEFAULT_FIRST,
+    };
+
+    let skip = match ctx.args.get("skip") {
+        Some(skip) => skip.u64()?,
+        None => 0,
+    };
+
+    Ok(Query { entity: entity.name.clone(), filters, order_by, order_direction, first, skip })
+}
+
+/// Builds a read-only GraphQL schema exposing the entities declared in the schema file.
+pub fn build_schema(store: Store, entities: Option<&EntitySchema>) -> Result<Schema, SchemaError> {
+    let mut entity_types: Vec<EntityType> = entities
+        .map(|entities| {
+            entities
+                .entities
+                .iter()
+                .map(|(name, entity)| EntityType {
+                    name: name.clone(),
+                    fields: entity
+                        .fields
+                        .iter()
+                        .map(|(field_name, field_type)| (field_name.clone(), *field_type))
+                        .collect(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    entity_types.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let meta = Object::new("_Meta_")
+        .field(Field::new("source", TypeRef::named_nn(TypeRef::STRING), |ctx| {
+            FieldFuture::new(async move {
+                let (source, _) = ctx.parent_value.try_downcast_ref::<(String, u64)>()?;
+                Ok(Some(GraphqlValue::from(source.clone())))
+            })
+        }))
+        .field(Field::new("block_number", TypeRef::named_nn(BIG_INT), |ctx| {
+            FieldFuture::new(async move {
+                let (_, block_number) = ctx.parent_value.try_downcast_ref::<(String, u64)>()?;
+                Ok(Some(GraphqlValue::from(block_number.to_string())))
+            })
+        }));
+
+    let meta_store = store.clone();
+    let mut query = Object::new("Query").field(Field::new(
+        "_meta",
+        TypeRef::named_nn_list_nn("_Meta_"),
+        move |_| {
+            let store = meta_store.clone();
+
+            FieldFuture::new(async move {
+                let checkpoints = store.checkpoints().await?;
+                Ok(Some(FieldValue::list(checkpoints.into_iter().map(FieldValue::owned_any))))
+            })
+        },
+    ));
+
+    let mut schema = Schema::build("Query", None, None)
+        .register(Scalar::new(BIG_INT))
+        .register(Enum::new("OrderDirection").item("asc").item("desc"))
+        .register(meta);
+
+    for entity in entity_types {
+        let single_store = store.clone();
+        let single_entity = entity.clone();
+
+        query = query.field(
+            Field::new(lowercase_first(&entity.name), TypeRef::named(&entity.name), move |ctx| {
+                let store = single_store.clone();
+                let entity = single_entity.clone();
+
+                FieldFuture::new(async move {
+                    let id = ctx.args.try_get("id")?.string()?.to_string();
+
+                    let query = Query {
+                        entity: entity.name.clone(),
+                        filters: vec![Filter::Id(id)],
+                        order_by: OrderBy::Id,
+                        order_direction: OrderDirection::Asc,
+                        first: 1,
+                        skip: 0,
+                    };
+
+                    let row = store.query(&query).await?.into_iter().next();
+                    Ok(row.map(FieldValue::owned_any))
+                })
+            })
+            .argument(InputValue::new("id", TypeRef::named_nn(TypeRef::ID))),
+        );
+
+        let list_store = store.clone();
+        let list_entity = entity.clone();
+
+        query = query.field(
+            Field::new(
+                format!("{}s", lowercase_first(&entity.name)),
+                TypeRef::named_nn_list_nn(&entity.name),
+                move |ctx| {
+                    let store = list_store.clone();
+                    let entity = list_entity.clone();
+
+                    FieldFuture::new(async move {
+                        let query = get_query(&entity, &ctx)?;
+                        let rows = store.query(&query).await?;
+                        Ok(Some(FieldValue::list(rows.into_iter().map(FieldValue::owned_any))))
+                    })
+                },
+            )
+            .argument(InputValue::new("where", TypeRef::named(format!("{}_filter", entity.name))))
+            .argument(InputValue::new(
+                "orderBy",
+                TypeRef::named(format!("{}_orderBy", entity.name)),
+            ))
+            .argument(InputValue::new("orderDirection", TypeRef::named("OrderDirection")))
+            .argument(InputValue::new("first", TypeRef::named(TypeRef::INT)))
+            .argument(InputValue::new("skip", TypeRef::named(TypeRef::INT))),
+        );
+
+        schema = schema
+            .register(entity_object(&entity))
+            .register(entity_filter(&entity))
+            .register(entity_order_by(&entity));
+    }
+
+    schema.register(query).finish()
+}
+
+async fn handle_request(
+    schema: Schema,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if request.method() != Method::POST {
+        let mut response = Response::new(Full::new(Bytes::new()));
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+        return Ok(response);
+    }
+
+    let body = request.into_body().collect().await?.to_bytes();
+
+    let response = match serde_json::from_slice::<async_graphql::Request>(&body) {
+        Ok(graphql_request) => schema.execute(graphql_request).await,
+        Err(error) => {
+            async_graphql::Response::from_errors(vec![ServerError::new(error.to_string(), None)])
+        }
+    };
+
+    let body = serde_json::to_vec(&response).unwrap_or_default();
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+    Ok(response)
+}
+
+pub async fn serve(schema: Schema, host: &str, port: u16) -> std::io::Result<()> {
+    let listener = TcpListener::bind((host, port)).await?;
+
+    println!("GraphQL server listening on {}:{}", host, port);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let schema = schema.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle_request(schema.clone(), request));
+
+            if let Err(error) =
+                http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+            {
+                eprintln!("Error serving GraphQL connection: {error}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Change, StartedTemplate, StoreBackend, StoreError};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    /// Backend recording the queries sent by the resolvers.
+    #[derive(Default)]
+    struct QueryRecorder {
+        queries: Mutex<Vec<Query>>,
+    }
+
+    #[async_trait]
+    impl StoreBackend for QueryRecorder {
+        async fn migrate(&self) -> Result<(), StoreError> {
+            Ok(())
+        }
+
+        async fn load(&self, _entity: &str, _id: &str) -> Result<Option<Value>, StoreError> {
+            Ok(None)
+        }
+
+        async fn query(&self, query: &Query) -> Result<Vec<Row>, StoreError> {
+            self.queries.lock().unwrap().push(query.clone());
+            Ok(Vec::new())
+        }
+
+        async fn checkpoint(&self, _source: &str) -> Result<Option<u64>, StoreError> {
+            Ok(None)
+        }
+
+        async fn checkpoints(&self) -> Result<Vec<(String, u64)>, StoreError> {
+            Ok(Vec::new())
+        }
+
+        async fn templates(&self) -> Result<Vec<StartedTemplate>, StoreError> {
+            Ok(Vec::new())
+        }
+
+        async fn commit(
+            &self,
+            _source: &str,
+            _block_number: u64,
+            _changes: Vec<Change>,
+        ) -> Result<(), StoreError> {
+            Ok(())
+        }
+    }
+
+    fn schema(recorder: Arc<QueryRecorder>) -> Schema {
+        let entities: EntitySchema = serde_json::from_value(json!({
+            "entities": {
+                "Deposit": {
+                    "fields": {
+                        "user": "address",
+                        "name": "string",
+                        "assets": "uint256",
+                        "delta": "int256"
+                    }
+                }
+            }
+        }))
+        .unwrap();
+
+        build_schema(Store::new(recorder), Some(&entities)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn ignores_case_only_for_hex_fields() {
+        let recorder = Arc::new(QueryRecorder::default());
+        let schema = schema(recorder.clone());
+
+        let response =
+            schema.execute(r#"{ deposits(where: { user: "0xAbC", name: "Alice" }) { id } }"#).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        let queries = recorder.queries.lock().unwrap();
+        let filters = &queries[0].filters;
+
+        assert!(filters.iter().any(|filter| matches!(
+            filter,
+            Filter::FieldIgnoreCase(name, value) if name == "user" && value == "0xAbC"
+        )));
+        assert!(filters.iter().any(|filter| matches!(
+            filter,
+            Filter::Field(name, value) if name == "name" && value == "Alice"
+        )));
+    }
+
+    #[tokio::test]
+    async fn orders_uint256_by_value_and_rejects_int256() {
+        let recorder = Arc::new(QueryRecorder::default());
+        let schema = schema(recorder.clone());
+
+        let response = schema.execute("{ deposits(orderBy: assets) { id } }").await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        assert!(matches!(
+            &recorder.queries.lock().unwrap()[0].order_by,
+            OrderBy::Uint256(name) if name == "assets"
+        ));
+
+        let response = schema.execute("{ deposits(orderBy: delta) { id } }").await;
+        assert!(!response.errors.is_empty());
+        assert_eq!(recorder.queries.lock().unwrap().len(), 1);
+    }
+}
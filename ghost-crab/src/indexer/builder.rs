@@ -0,0 +1,207 @@
+use super::indexer::Indexer;
+use ghost_crab_common::config::{
+    BlockHandlerConfig, CacheConfig, Config, DataSource, GraphqlConfig, NetworkConfig, Template,
+    TransactionHandlerConfig,
+};
+
+/// Builds the config of an indexer in code, e.g. from a control plane, instead
+/// of loading it from a file. The sources are named after the handlers, so the
+/// handlers generated by the macros can be loaded as usual.
+///
+/// ```ignore
+/// let mut indexer = Indexer::builder()
+///     .network("ethereum", NetworkConfig::new(rpc_url, 25))
+///     .data_source(
+///         "ETHx",
+///         DataSource::new("abis/ETHx.json", "0xA35b...A15b", 17416153, "ethereum"),
+///     )
+///     .build();
+///
+/// indexer.load_event_handler(ETHxTransfer::new()).await?;
+/// ```
+#[derive(Default)]
+pub struct IndexerBuilder {
+    config: Config,
+}
+
+impl IndexerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts from an existing config, e.g. to add sources to a loaded file.
+    pub fn from_config(config: Config) -> Self {
+        IndexerBuilder { config }
+    }
+
+    pub fn network(mut self, name: impl Into<String>, network: NetworkConfig) -> Self {
+        self.config.networks.insert(name.into(), network);
+        self
+    }
+
+    pub fn data_source(mut self, name: impl Into<String>, data_source: DataSource) -> Self {
+        self.config.data_sources.insert(name.into(), data_source);
+        self
+    }
+
+    pub fn template(mut self, name: impl Into<String>, template: Template) -> Self {
+        self.config.templates.insert(name.into(), template);
+        self
+    }
+
+    pub fn block_handler(mut self, name: impl Into<String>, config: BlockHandlerConfig) -> Self {
+        self.config.block_handlers.insert(name.into(), config);
+        self
+    }
+
+    pub fn transaction_handler(
+        mut self,
+        name: impl Into<String>,
+        config: TransactionHandlerConfig,
+    ) -> Self {
+        self.config.transaction_handlers.insert(name.into(), config);
+        self
+    }
+
+    /// Sets the URL of the database where the entities are stored.
+    pub fn database(mut self, database: impl Into<String>) -> Self {
+        self.config.database = Some(database.into());
+        self
+    }
+
+    /// Sets the path of the schema of the entities.
+    pub fn schema(mut self, schema: impl Into<String>) -> Self {
+        self.config.schema = Some(schema.into());
+        self
+    }
+
+    pub fn graphql(mut self, graphql: GraphqlConfig) -> Self {
+        self.config.graphql = Some(graphql);
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.config.cache = cache;
+        self
+    }
+
+    pub fn build(self) -> Indexer {
+        Indexer::with_config(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_handler::{BlockContext, BlockHandler};
+    use crate::event_handler::{EventContext, EventHandler, HandlerResult};
+    use crate::indexer::error::Error;
+    use crate::transaction_handler::{TransactionContext, TransactionHandler};
+    use async_trait::async_trait;
+    use ghost_crab_common::config::{CacheBackendKind, TransactionDirection};
+    use std::sync::Arc;
+
+    const VAULT: &str = "0x0000000000000000000000000000000000000001";
+
+    struct VaultDeposit;
+
+    #[async_trait]
+    impl EventHandler for VaultDeposit {
+        async fn handle(&self, _ctx: EventContext) -> HandlerResult {
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "Vault".to_string()
+        }
+
+        fn event_signature(&self) -> String {
+            "Deposit(address,uint256)".to_string()
+        }
+    }
+
+    struct VaultBlocks;
+
+    #[async_trait]
+    impl BlockHandler for VaultBlocks {
+        async fn handle(&self, _ctx: BlockContext) -> HandlerResult {
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "VaultBlocks".to_string()
+        }
+    }
+
+    struct VaultWithdraw;
+
+    #[async_trait]
+    impl TransactionHandler for VaultWithdraw {
+        async fn handle(&self, _ctx: TransactionContext) -> HandlerResult {
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "VaultCalls".to_string()
+        }
+
+        fn function_selector(&self) -> Option<[u8; 4]> {
+            Some([0xba, 0x08, 0x76, 0x52])
+        }
+    }
+
+    fn builder() -> IndexerBuilder {
+        let cache = CacheConfig { backend: Some(CacheBackendKind::Memory), ..Default::default() };
+
+        IndexerBuilder::new()
+            .network("ethereum", NetworkConfig::new("http://localhost:8545", 25))
+            .cache(cache)
+            .data_source("Vault", DataSource::new("abis/Vault.json", VAULT, 100, "ethereum"))
+            .block_handler("VaultBlocks", BlockHandlerConfig::new(100, "ethereum", 10))
+            .transaction_handler(
+                "VaultCalls",
+                TransactionHandlerConfig::new(VAULT, 100, "ethereum"),
+            )
+    }
+
+    #[tokio::test]
+    async fn loads_the_handlers_of_the_sources() {
+        let mut indexer = builder().build();
+
+        indexer.load_event_handler(Arc::new(Box::new(VaultDeposit))).await.unwrap();
+        indexer.load_block_handler(Arc::new(Box::new(VaultBlocks))).await.unwrap();
+        indexer.load_transaction_handler(Arc::new(Box::new(VaultWithdraw))).await.unwrap();
+
+        // Each source is loaded once
+        let result = indexer.load_event_handler(Arc::new(Box::new(VaultDeposit))).await;
+        assert!(matches!(result, Err(Error::NotFound(name)) if name == "Vault"));
+    }
+
+    #[tokio::test]
+    async fn checks_the_sources_built_in_code() {
+        let mut indexer = IndexerBuilder::new().build();
+        let result = indexer.load_block_handler(Arc::new(Box::new(VaultBlocks))).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+
+        let mut indexer =
+            builder().data_source("Vault", DataSource::new("", VAULT, 100, "base")).build();
+        let result = indexer.load_event_handler(Arc::new(Box::new(VaultDeposit))).await;
+        assert!(matches!(result, Err(Error::NetworkNotFound(network)) if network == "base"));
+
+        let mut calls = TransactionHandlerConfig::new(VAULT, 100, "ethereum");
+        calls.direction = TransactionDirection::From;
+
+        let mut indexer = builder().transaction_handler("VaultCalls", calls).build();
+        let result = indexer.load_transaction_handler(Arc::new(Box::new(VaultWithdraw))).await;
+        assert!(matches!(result, Err(Error::FunctionFromAddress(_))));
+    }
+
+    #[cfg(feature = "graphql")]
+    #[tokio::test]
+    async fn needs_a_database_to_serve_graphql() {
+        let indexer = builder().graphql(GraphqlConfig::new(0)).build();
+
+        let result = indexer.start().await;
+        assert!(matches!(result, Err(Error::GraphQL(_))));
+    }
+}
@@ -0,0 +1,199 @@
+use super::{CacheBackend, CacheEntries, CacheError, CacheKeys, CacheWrite};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of the next temporary file, unique within the process.
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Cache storing each entry in its own file, which doesn't need a native
+/// library, and can be shared between processes through a shared directory.
+pub struct FilesystemCache {
+    path: PathBuf,
+}
+
+impl FilesystemCache {
+    pub fn open(path: &Path) -> Result<FilesystemCache, CacheError> {
+        let path = path.join("entries");
+        fs::create_dir_all(&path).map_err(CacheError::Io)?;
+
+        Ok(FilesystemCache { path })
+    }
+
+    /// Opens an existing cache without creating its directory.
+    pub fn open_read_only(path: &Path) -> Result<FilesystemCache, CacheError> {
+        let path = path.join("entries");
+        fs::metadata(&path).map_err(CacheError::Io)?;
+
+        Ok(FilesystemCache { path })
+    }
+
+    fn file(&self, key: &[u8]) -> PathBuf {
+        self.path.join(encode_key(key))
+    }
+}
+
+/// Escapes the bytes of the key that are not safe in a file name.
+fn encode_key(key: &[u8]) -> String {
+    let mut name = String::with_capacity(key.len());
+
+    for byte in key {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-' | b'#' => {
+                name.push(*byte as char)
+            }
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    name
+}
+
+fn decode_key(name: &str) -> Option<Vec<u8>> {
+    let mut key = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                key.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            byte => key.push(byte),
+        }
+    }
+
+    Some(key)
+}
+
+impl CacheBackend for FilesystemCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CacheError> {
+        match fs::read(self.file(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(CacheError::Io(error)),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CacheError> {
+        let file = self.file(key);
+
+        // Written to a temporary file first, so readers never see a partial entry. Its
+        // name is unique to the write, as other threads or processes may write the entry
+        let number = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+        let temporary = file.with_extension(format!("tmp{}-{}", std::process::id(), number));
+        fs::write(&temporary, value).map_err(CacheError::Io)?;
+        fs::rename(&temporary, &file).map_err(CacheError::Io)
+    }
+
+    fn write(&self, writes: Vec<CacheWrite>) -> Result<(), CacheError> {
+        for write in writes {
+            match write {
+                CacheWrite::Put(key, value) => self.put(&key, &value)?,
+                CacheWrite::Delete(key) => match fs::remove_file(self.file(&key)) {
+                    Err(error) if error.kind() != ErrorKind::NotFound => {
+                        return Err(CacheError::Io(error))
+                    }
+                    _ => {}
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn entries(&self, prefix: &[u8]) -> CacheEntries<'_> {
+        Box::new(self.keys(prefix).filter_map(|key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(error) => return Some(Err(error)),
+            };
+
+            match fs::read(self.file(&key)) {
+                Ok(value) => Some(Ok((key, value))),
+                Err(error) if error.kind() == ErrorKind::NotFound => None,
+                Err(error) => Some(Err(CacheError::Io(error))),
+            }
+        }))
+    }
+
+    /// Lists the names of the files, without reading them.
+    fn keys(&self, prefix: &[u8]) -> CacheKeys<'_> {
+        let directory = match fs::read_dir(&self.path) {
+            Ok(directory) => directory,
+            Err(error) => return Box::new(std::iter::once(Err(CacheError::Io(error)))),
+        };
+
+        let mut keys: Vec<Vec<u8>> = directory
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+
+                // Skips the temporary files of the writes in progress
+                match name.contains('.') {
+                    true => None,
+                    false => decode_key(&name),
+                }
+            })
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+
+        keys.sort();
+
+        Box::new(keys.into_iter().map(Ok))
+    }
+
+    fn size(&self) -> Result<u64, CacheError> {
+        let mut size = 0;
+
+        for entry in fs::read_dir(&self.path).map_err(CacheError::Io)? {
+            let metadata = entry.and_then(|entry| entry.metadata()).map_err(CacheError::Io)?;
+            size += metadata.len();
+        }
+
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn writes_the_same_entry_concurrently() {
+        let path =
+            std::env::temp_dir().join(format!("ghost-crab-concurrent-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let cache = Arc::new(FilesystemCache::open(&path).unwrap());
+
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        cache.put(b"eth_call:0:hash", &[1; 4096]).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        assert_eq!(cache.get(b"eth_call:0:hash").unwrap(), Some(vec![1; 4096]));
+        assert_eq!(fs::read_dir(path.join("entries")).unwrap().count(), 1);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn encodes_the_keys_as_file_names() {
+        let key = b"eth_getLogs:00000000000000ff:a/b c.d";
+        let name = encode_key(key);
+
+        assert!(!name.contains(['/', ' ', '.']));
+        assert_eq!(decode_key(&name), Some(key.to_vec()));
+    }
+}
@@ -0,0 +1,61 @@
+use super::{CacheBackend, CacheEntries, CacheError, CacheWrite};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// Cache kept in memory, lost when the process exits.
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> MemoryCache {
+        MemoryCache::default()
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self.entries.read().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CacheError> {
+        self.entries.write().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn write(&self, writes: Vec<CacheWrite>) -> Result<(), CacheError> {
+        let mut entries = self.entries.write().unwrap();
+
+        for write in writes {
+            match write {
+                CacheWrite::Put(key, value) => {
+                    entries.insert(key, value);
+                }
+                CacheWrite::Delete(key) => {
+                    entries.remove(&key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn entries(&self, prefix: &[u8]) -> CacheEntries<'_> {
+        let entries: Vec<_> = self
+            .entries
+            .read()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+
+        Box::new(entries.into_iter())
+    }
+
+    fn size(&self) -> Result<u64, CacheError> {
+        let entries = self.entries.read().unwrap();
+        Ok(entries.iter().map(|(key, value)| (key.len() + value.len()) as u64).sum())
+    }
+}
@@ -0,0 +1,598 @@
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb;
+
+pub mod filesystem;
+pub mod memory;
+
+use super::error::{Error, Result};
+pub use crate::layers::cache_key::cache_key;
+use crate::layers::cache_key::{
+    access_key, migrate_key, parse_cache_key, ACCESS_PREFIX, VERSION, VERSION_KEY,
+};
+use core::fmt;
+use ghost_crab_common::config::{CacheBackendKind, CacheConfig, CacheMode, EvictionPolicy};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum CacheError {
+    #[cfg(feature = "rocksdb")]
+    RocksDb(::rocksdb::Error),
+    Io(std::io::Error),
+    UnsupportedBackend(CacheBackendKind),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "rocksdb")]
+            CacheError::RocksDb(error) => write!(f, "RocksDB error: {}", error),
+            CacheError::Io(error) => write!(f, "IO error: {}", error),
+            CacheError::UnsupportedBackend(kind) => {
+                write!(f, "The {:?} cache backend is not enabled, check the crate features", kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+/// A write applied by [`CacheBackend::write`].
+#[derive(Debug, Clone)]
+pub enum CacheWrite {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+impl CacheWrite {
+    pub fn put(key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> CacheWrite {
+        CacheWrite::Put(key.into(), value.into())
+    }
+
+    pub fn delete(key: impl Into<Vec<u8>>) -> CacheWrite {
+        CacheWrite::Delete(key.into())
+    }
+}
+
+pub type CacheEntries<'a> =
+    Box<dyn Iterator<Item = core::result::Result<(Vec<u8>, Vec<u8>), CacheError>> + 'a>;
+
+pub type CacheKeys<'a> = Box<dyn Iterator<Item = core::result::Result<Vec<u8>, CacheError>> + 'a>;
+
+/// Key-value store where the responses of the RPC requests are cached.
+pub trait CacheBackend {
+    fn get(&self, key: &[u8]) -> core::result::Result<Option<Vec<u8>>, CacheError>;
+
+    fn put(&self, key: &[u8], value: &[u8]) -> core::result::Result<(), CacheError>;
+
+    /// Applies the writes in order.
+    fn write(&self, writes: Vec<CacheWrite>) -> core::result::Result<(), CacheError>;
+
+    /// Returns the entries whose key starts with the prefix, sorted by key.
+    fn entries(&self, prefix: &[u8]) -> CacheEntries<'_>;
+
+    /// Returns the keys starting with the prefix, sorted, for the backends
+    /// that can list them without reading the values.
+    fn keys(&self, prefix: &[u8]) -> CacheKeys<'_> {
+        Box::new(self.entries(prefix).map(|entry| entry.map(|(key, _)| key)))
+    }
+
+    /// Returns the size of the cache in bytes.
+    fn size(&self) -> core::result::Result<u64, CacheError>;
+
+    /// Reclaims the space of the deleted entries.
+    fn compact(&self) {}
+}
+
+pub type CacheBackendInstance = Arc<dyn CacheBackend + Send + Sync>;
+
+#[cfg(feature = "rocksdb")]
+pub const DEFAULT_BACKEND: CacheBackendKind = CacheBackendKind::RocksDb;
+#[cfg(not(feature = "rocksdb"))]
+pub const DEFAULT_BACKEND: CacheBackendKind = CacheBackendKind::Filesystem;
+
+pub fn load_cache(network: &str, config: &CacheConfig) -> Result<CacheBackendInstance> {
+    let current_dir = std::env::current_dir().map_err(|e| Error::CacheFileNotFound(e))?;
+
+    let directory = match config.mode {
+        CacheMode::Live | CacheMode::Offline => "cache",
+        CacheMode::Record | CacheMode::Replay => "fixtures",
+    };
+
+    let cache_path = current_dir.join(directory).join(network);
+
+    open_cache(&cache_path, config.backend.unwrap_or(DEFAULT_BACKEND))
+}
+
+pub fn open_cache(path: &Path, kind: CacheBackendKind) -> Result<CacheBackendInstance> {
+    let cache: CacheBackendInstance = match kind {
+        #[cfg(feature = "rocksdb")]
+        CacheBackendKind::RocksDb => {
+            Arc::new(rocksdb::RocksDbCache::open(path).map_err(Error::Cache)?)
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        CacheBackendKind::RocksDb => {
+            return Err(Error::Cache(CacheError::UnsupportedBackend(kind)));
+        }
+        CacheBackendKind::Filesystem => {
+            Arc::new(filesystem::FilesystemCache::open(path).map_err(Error::Cache)?)
+        }
+        CacheBackendKind::Memory => Arc::new(memory::MemoryCache::new()),
+    };
+
+    migrate_cache(cache.as_ref())?;
+
+    Ok(cache)
+}
+
+/// Opens a cache to read its entries, without migrating it. The keys of a
+/// cache that was not migrated yet are not found.
+pub fn open_cache_read_only(path: &Path, kind: CacheBackendKind) -> Result<CacheBackendInstance> {
+    let cache: CacheBackendInstance = match kind {
+        #[cfg(feature = "rocksdb")]
+        CacheBackendKind::RocksDb => {
+            Arc::new(rocksdb::RocksDbCache::open_read_only(path).map_err(Error::Cache)?)
+        }
+        #[cfg(not(feature = "rocksdb"))]
+        CacheBackendKind::RocksDb => {
+            return Err(Error::Cache(CacheError::UnsupportedBackend(kind)));
+        }
+        CacheBackendKind::Filesystem => {
+            Arc::new(filesystem::FilesystemCache::open_read_only(path).map_err(Error::Cache)?)
+        }
+        CacheBackendKind::Memory => Arc::new(memory::MemoryCache::new()),
+    };
+
+    if cache.get(VERSION_KEY.as_bytes()).map_err(Error::Cache)?.is_none() {
+        eprintln!("The cache keys have an older format, run the indexer once to migrate them");
+    }
+
+    Ok(cache)
+}
+
+/// Rewrites the keys of a cache created before the keys were hashed.
+fn migrate_cache(cache: &dyn CacheBackend) -> Result<()> {
+    if cache.get(VERSION_KEY.as_bytes()).map_err(Error::Cache)?.is_some() {
+        return Ok(());
+    }
+
+    let mut migrated = 0;
+    let mut batch = Vec::new();
+
+    for entry in cache.entries(b"") {
+        let (key, value) = entry.map_err(Error::Cache)?;
+
+        if let Some(new_key) = migrate_key(&key) {
+            batch.push(CacheWrite::put(new_key, value));
+            batch.push(CacheWrite::delete(key));
+            migrated += 1;
+        }
+
+        if batch.len() >= 10_000 {
+            cache.write(std::mem::take(&mut batch)).map_err(Error::Cache)?;
+        }
+    }
+
+    batch.push(CacheWrite::put(VERSION_KEY, VERSION));
+    cache.write(batch).map_err(Error::Cache)?;
+
+    if migrated > 0 {
+        println!("Migrated {} cache entries to the new key format", migrated);
+    }
+
+    Ok(())
+}
+
+/// Returns the highest block number of the block ranges and blocks in the cache.
+pub fn cached_head(cache: &dyn CacheBackend) -> Option<u64> {
+    cache
+        .keys(b"")
+        .filter_map(|key| {
+            let key = key.ok()?;
+
+            match parse_cache_key(&key)? {
+                ("eth_getLogs" | "trace_filter" | "eth_getBlockByNumber", block_number) => {
+                    Some(block_number)
+                }
+                _ => None,
+            }
+        })
+        .max()
+}
+
+/// Applies the retention policies, then evicts entries until the cache fits
+/// in its maximum size. Returns the number of evicted entries.
+pub fn evict(cache: &dyn CacheBackend, config: &CacheConfig) -> Result<usize> {
+    let mut evicted = apply_retention(cache, config)?;
+
+    if let Some(max_size_mb) = config.max_size_mb {
+        evicted += evict_to_size(cache, config.eviction, max_size_mb * 1024 * 1024)?;
+    }
+
+    if evicted > 0 {
+        cache.compact();
+    }
+
+    Ok(evicted)
+}
+
+fn apply_retention(cache: &dyn CacheBackend, config: &CacheConfig) -> Result<usize> {
+    let mut batch = Vec::new();
+
+    for (method, policy) in &config.retention {
+        let prefix = format!("{method}:");
+        let mut entries = Vec::new();
+
+        for key in cache.keys(prefix.as_bytes()) {
+            let key = key.map_err(Error::Cache)?;
+
+            if let Some((_, block_number)) = parse_cache_key(&key) {
+                entries.push((block_number, key));
+            }
+        }
+
+        let threshold = match (policy.cache, policy.blocks) {
+            (false, _) => u64::MAX,
+            (true, Some(blocks)) => {
+                let head = entries.iter().map(|(block_number, _)| *block_number).max();
+                head.unwrap_or_default().saturating_sub(blocks)
+            }
+            (true, None) => continue,
+        };
+
+        for (block_number, key) in entries {
+            // Entries not tied to a block are only removed when the method is not cached
+            if block_number < threshold && (block_number > 0 || !policy.cache) {
+                batch.push(CacheWrite::delete(access_key(&key)));
+                batch.push(CacheWrite::delete(key));
+            }
+        }
+    }
+
+    let evicted = batch.len() / 2;
+    cache.write(batch).map_err(Error::Cache)?;
+
+    Ok(evicted)
+}
+
+/// Share of the maximum size the cache is evicted down to, so it isn't scanned
+/// again as soon as a few entries are added.
+const EVICTION_TARGET: f64 = 0.9;
+
+fn evict_to_size(cache: &dyn CacheBackend, policy: EvictionPolicy, max_size: u64) -> Result<usize> {
+    let size = cache.size().map_err(Error::Cache)?;
+
+    if size <= max_size {
+        return Ok(0);
+    }
+
+    // A single scan reads the entries along with the time of their last read
+    let mut total = 0;
+    let mut last_reads = HashMap::new();
+    let mut entries = Vec::new();
+
+    for entry in cache.entries(b"") {
+        let (key, value) = entry.map_err(Error::Cache)?;
+        let entry_size = (key.len() + value.len()) as u64;
+        total += entry_size;
+
+        if let Some(key) = key.strip_prefix(ACCESS_PREFIX.as_bytes()) {
+            let last_read = value.as_slice().try_into().map(u64::from_be_bytes).unwrap_or_default();
+            last_reads.insert(key.to_vec(), (last_read, entry_size));
+            continue;
+        }
+
+        if let Some((_, block_number)) = parse_cache_key(&key) {
+            entries.push((block_number, entry_size, key));
+        }
+    }
+
+    // Entries are evicted by increasing rank: the block number or the time of the last read
+    let mut entries: Vec<(u64, u64, Vec<u8>)> = entries
+        .into_iter()
+        .map(|(block_number, entry_size, key)| {
+            let (last_read, access_size) = last_reads.get(&key).copied().unwrap_or_default();
+
+            let rank = match policy {
+                EvictionPolicy::OldestBlock => block_number,
+                EvictionPolicy::LeastRecentlyUsed => last_read,
+            };
+
+            (rank, entry_size + access_size, key)
+        })
+        .collect();
+
+    entries.sort();
+
+    // The size of the backend includes its own overhead (e.g. compression or
+    // file metadata), so the bytes to free are measured in entries, in the
+    // same proportion
+    let target = (max_size as f64 * EVICTION_TARGET) as u64;
+    let excess = (total as u128 * (size - target) as u128).div_ceil(size as u128) as u64;
+
+    let mut freed = 0;
+    let mut evicted = 0;
+    let mut batch = Vec::new();
+
+    for (_, entry_size, key) in entries {
+        if freed >= excess {
+            break;
+        }
+
+        batch.push(CacheWrite::delete(access_key(&key)));
+        batch.push(CacheWrite::delete(key));
+        freed += entry_size;
+        evicted += 1;
+
+        if batch.len() >= 10_000 {
+            cache.write(std::mem::take(&mut batch)).map_err(Error::Cache)?;
+        }
+    }
+
+    cache.write(batch).map_err(Error::Cache)?;
+
+    Ok(evicted)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MethodStats {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// Returns the number of entries and their size, by RPC method.
+pub fn stats(cache: &dyn CacheBackend) -> Result<BTreeMap<String, MethodStats>> {
+    let mut stats = BTreeMap::<String, MethodStats>::new();
+
+    for entry in cache.entries(b"") {
+        let (key, value) = entry.map_err(Error::Cache)?;
+
+        if let Some((method, _)) = parse_cache_key(&key) {
+            let method_stats = stats.entry(method.to_string()).or_default();
+            method_stats.entries += 1;
+            method_stats.bytes += (key.len() + value.len()) as u64;
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Returns the size of the cache.
+pub fn size(cache: &dyn CacheBackend) -> Result<u64> {
+    cache.size().map_err(Error::Cache)
+}
+
+/// Entries to remove from the cache. Every condition set must match.
+#[derive(Debug, Default, Clone)]
+pub struct PruneFilter {
+    pub method: Option<String>,
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+}
+
+/// Removes the matching entries, and returns how many were removed.
+pub fn prune(cache: &dyn CacheBackend, filter: &PruneFilter) -> Result<usize> {
+    let mut pruned = 0;
+    let mut batch = Vec::new();
+
+    for key in cache.keys(b"") {
+        let key = key.map_err(Error::Cache)?;
+
+        let Some((method, block_number)) = parse_cache_key(&key) else {
+            continue;
+        };
+
+        let matches = filter.method.as_ref().is_none_or(|filter| filter == method)
+            && filter.from_block.is_none_or(|from_block| block_number >= from_block)
+            && filter.to_block.is_none_or(|to_block| block_number <= to_block);
+
+        if matches {
+            batch.push(CacheWrite::delete(access_key(&key)));
+            batch.push(CacheWrite::delete(key));
+            pruned += 1;
+        }
+
+        if batch.len() >= 10_000 {
+            cache.write(std::mem::take(&mut batch)).map_err(Error::Cache)?;
+        }
+    }
+
+    cache.write(batch).map_err(Error::Cache)?;
+
+    if pruned > 0 {
+        cache.compact();
+    }
+
+    Ok(pruned)
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportedEntry {
+    key: String,
+    value: String,
+}
+
+/// Writes the entries of the cache as JSON lines, and returns how many were written.
+pub fn export(cache: &dyn CacheBackend, writer: &mut impl Write) -> Result<usize> {
+    let mut exported = 0;
+
+    for entry in cache.entries(b"") {
+        let (key, value) = entry.map_err(Error::Cache)?;
+
+        if parse_cache_key(&key).is_none() {
+            continue;
+        }
+
+        let entry = ExportedEntry {
+            key: String::from_utf8_lossy(&key).into_owned(),
+            value: String::from_utf8_lossy(&value).into_owned(),
+        };
+
+        serde_json::to_writer(&mut *writer, &entry).map_err(Error::CacheFile)?;
+        writer.write_all(b"\n").map_err(Error::Io)?;
+        exported += 1;
+    }
+
+    writer.flush().map_err(Error::Io)?;
+
+    Ok(exported)
+}
+
+/// Reads the entries written by [`export`] into the cache, and returns how many were read.
+pub fn import(cache: &dyn CacheBackend, reader: impl BufRead) -> Result<usize> {
+    let mut imported = 0;
+    let mut batch = Vec::new();
+
+    for line in reader.lines() {
+        let line = line.map_err(Error::Io)?;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry: ExportedEntry = serde_json::from_str(&line).map_err(Error::CacheFile)?;
+        batch.push(CacheWrite::put(entry.key, entry.value));
+        imported += 1;
+
+        if batch.len() >= 10_000 {
+            cache.write(std::mem::take(&mut batch)).map_err(Error::Cache)?;
+        }
+    }
+
+    cache.write(batch).map_err(Error::Cache)?;
+
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn block_key(block_number: u64) -> String {
+        cache_key("eth_getBlockByNumber", &json!([format!("{block_number:#x}"), false]))
+    }
+
+    fn cache_with_blocks(blocks: u64) -> memory::MemoryCache {
+        let cache = memory::MemoryCache::new();
+
+        for block_number in 1..=blocks {
+            cache.put(block_key(block_number).as_bytes(), &[0; 100]).unwrap();
+        }
+
+        cache
+    }
+
+    fn has_block(cache: &dyn CacheBackend, block_number: u64) -> bool {
+        cache.get(block_key(block_number).as_bytes()).unwrap().is_some()
+    }
+
+    #[test]
+    fn evicts_the_oldest_blocks_below_the_size() {
+        let cache = cache_with_blocks(10);
+        let max_size = cache.size().unwrap() / 2;
+
+        let evicted = evict_to_size(&cache, EvictionPolicy::OldestBlock, max_size).unwrap();
+
+        assert_eq!(evicted, 6);
+        assert!(cache.size().unwrap() <= (max_size as f64 * EVICTION_TARGET) as u64);
+        assert!(!has_block(&cache, 6));
+        assert!(has_block(&cache, 7));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_read_with_their_reads() {
+        let cache = cache_with_blocks(4);
+
+        // The oldest blocks were read last
+        for (block_number, last_read) in [(1, 4u64), (2, 3), (3, 2), (4, 1)] {
+            let key = access_key(block_key(block_number).as_bytes());
+            cache.put(&key, &last_read.to_be_bytes()).unwrap();
+        }
+
+        let max_size = cache.size().unwrap() * 3 / 4;
+        let evicted = evict_to_size(&cache, EvictionPolicy::LeastRecentlyUsed, max_size).unwrap();
+
+        assert_eq!(evicted, 2);
+        assert!(has_block(&cache, 1) && has_block(&cache, 2));
+        assert!(!has_block(&cache, 3) && !has_block(&cache, 4));
+        assert_eq!(cache.entries(ACCESS_PREFIX.as_bytes()).count(), 2);
+    }
+
+    #[test]
+    fn skips_the_scan_below_the_size() {
+        let cache = cache_with_blocks(10);
+        let max_size = cache.size().unwrap();
+
+        assert_eq!(evict_to_size(&cache, EvictionPolicy::OldestBlock, max_size).unwrap(), 0);
+        assert!(has_block(&cache, 1));
+    }
+
+    #[test]
+    fn opens_read_only_without_migrating() {
+        let path =
+            std::env::temp_dir().join(format!("ghost-crab-read-only-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        assert!(open_cache_read_only(&path, CacheBackendKind::Filesystem).is_err());
+        assert!(!path.exists());
+
+        let legacy = filesystem::FilesystemCache::open(&path).unwrap();
+        legacy.put(b"legacy", b"value").unwrap();
+
+        let cache = open_cache_read_only(&path, CacheBackendKind::Filesystem).unwrap();
+        assert_eq!(cache.get(b"legacy").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(cache.get(VERSION_KEY.as_bytes()).unwrap(), None);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// Checks the behavior every backend must have.
+    fn check_backend(cache: &dyn CacheBackend) {
+        cache.put(b"b:1", b"one").unwrap();
+        cache.put(b"a:2", b"two").unwrap();
+        cache.put(b"b:0", b"zero").unwrap();
+
+        assert_eq!(cache.get(b"b:1").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(cache.get(b"missing").unwrap(), None);
+
+        let entries: Vec<_> = cache.entries(b"b:").map(|entry| entry.unwrap()).collect();
+        assert_eq!(
+            entries,
+            [(b"b:0".to_vec(), b"zero".to_vec()), (b"b:1".to_vec(), b"one".to_vec())]
+        );
+
+        let keys: Vec<_> = cache.keys(b"").map(|key| key.unwrap()).collect();
+        assert_eq!(keys, [b"a:2".to_vec(), b"b:0".to_vec(), b"b:1".to_vec()]);
+
+        cache
+            .write(vec![
+                CacheWrite::put(b"b:1".to_vec(), b"uno".to_vec()),
+                CacheWrite::delete(b"a:2".to_vec()),
+                CacheWrite::delete(b"missing".to_vec()),
+            ])
+            .unwrap();
+
+        assert_eq!(cache.get(b"b:1").unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(cache.get(b"a:2").unwrap(), None);
+        assert!(cache.size().unwrap() >= 7);
+    }
+
+    #[test]
+    fn memory_backend() {
+        check_backend(&memory::MemoryCache::new());
+    }
+
+    #[test]
+    fn filesystem_backend() {
+        let path = std::env::temp_dir().join(format!("ghost-crab-backend-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        check_backend(&filesystem::FilesystemCache::open(&path).unwrap());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use super::{CacheBackend, CacheEntries, CacheError, CacheWrite};
+use rocksdb::{DBCompressionType, IteratorMode, Options, WriteBatch, DB};
+use std::path::Path;
+
+pub struct RocksDbCache {
+    db: DB,
+}
+
+impl RocksDbCache {
+    pub fn open(path: &Path) -> Result<RocksDbCache, CacheError> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.set_compression_type(DBCompressionType::Lz4);
+        options.set_bottommost_compression_type(DBCompressionType::Zstd);
+
+        let db = DB::open(&options, path).map_err(CacheError::RocksDb)?;
+
+        Ok(RocksDbCache { db })
+    }
+
+    /// Opens the cache without taking its lock, so it can be inspected while
+    /// the indexer is running.
+    pub fn open_read_only(path: &Path) -> Result<RocksDbCache, CacheError> {
+        let db = DB::open_for_read_only(&Options::default(), path, false)
+            .map_err(CacheError::RocksDb)?;
+
+        Ok(RocksDbCache { db })
+    }
+}
+
+impl CacheBackend for RocksDbCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CacheError> {
+        self.db.get(key).map_err(CacheError::RocksDb)
+    }
+
+    fn put(&self, key: &[u8], value: &[u8]) -> Result<(), CacheError> {
+        self.db.put(key, value).map_err(CacheError::RocksDb)
+    }
+
+    fn write(&self, writes: Vec<CacheWrite>) -> Result<(), CacheError> {
+        let mut batch = WriteBatch::default();
+
+        for write in writes {
+            match write {
+                CacheWrite::Put(key, value) => batch.put(key, value),
+                CacheWrite::Delete(key) => batch.delete(key),
+            }
+        }
+
+        self.db.write(batch).map_err(CacheError::RocksDb)
+    }
+
+    fn entries(&self, prefix: &[u8]) -> CacheEntries<'_> {
+        let prefix = prefix.to_vec();
+
+        let iterator = match prefix.is_empty() {
+            true => self.db.iterator(IteratorMode::Start),
+            false => self.db.prefix_iterator(&prefix),
+        };
+
+        Box::new(
+            iterator
+                .take_while(move |entry| match entry {
+                    Ok((key, _)) => key.starts_with(&prefix),
+                    Err(_) => true,
+                })
+                .map(|entry| {
+                    entry
+                        .map(|(key, value)| (key.into_vec(), value.into_vec()))
+                        .map_err(CacheError::RocksDb)
+                }),
+        )
+    }
+
+    fn size(&self) -> Result<u64, CacheError> {
+        let size = self
+            .db
+            .property_int_value("rocksdb.total-sst-files-size")
+            .map_err(CacheError::RocksDb)?;
+
+        Ok(size.unwrap_or_default())
+    }
+
+    fn compact(&self) {
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+    }
+}
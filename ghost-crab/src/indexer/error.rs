@@ -1,14 +1,28 @@
+use super::cache::CacheError;
+use crate::store::StoreError;
 use alloy::hex::FromHexError;
 use core::fmt;
+use ghost_crab_common::config::ConfigError;
 
 #[derive(Debug)]
 pub enum Error {
     NotFound(String),
-    DB(rocksdb::Error),
+    Cache(CacheError),
     NetworkNotFound(String),
     InvalidAddress(FromHexError),
     CacheFileNotFound(std::io::Error),
+    EmptyCache(String),
+    CacheFile(serde_json::Error),
+    Io(std::io::Error),
     InvalidRpcUrl(Box<dyn std::error::Error>),
+    Store(StoreError),
+    GraphQL(Box<dyn std::error::Error>),
+    InvalidAbi(serde_json::Error),
+    EventNotFound(String),
+    AmbiguousEvent(String, Vec<String>),
+    InvalidEvent(alloy::dyn_abi::Error),
+    FunctionFromAddress(String),
+    Config(ConfigError),
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -19,7 +33,7 @@ impl fmt::Display for Error {
             Error::NotFound(handler) => {
                 writeln!(f, "Handler not found: {}", handler)
             }
-            Error::DB(error) => {
+            Error::Cache(error) => {
                 writeln!(f, "Error while loading cache: {}", error)
             }
             Error::NetworkNotFound(network) => {
@@ -31,9 +45,51 @@ impl fmt::Display for Error {
             Error::CacheFileNotFound(error) => {
                 writeln!(f, "Cache file not found: {}", error)
             }
+            Error::CacheFile(error) => {
+                writeln!(f, "Invalid cache file: {}", error)
+            }
+            Error::Io(error) => {
+                writeln!(f, "IO error: {}", error)
+            }
+            Error::EmptyCache(network) => {
+                writeln!(f, "No cached blocks to run offline for network: {}", network)
+            }
             Error::InvalidRpcUrl(error) => {
                 writeln!(f, "Invalid RPC url: {}", error)
             }
+            Error::Store(error) => {
+                writeln!(f, "Error while loading store: {}", error)
+            }
+            Error::GraphQL(error) => {
+                writeln!(f, "Error while loading GraphQL server: {}", error)
+            }
+            Error::InvalidAbi(error) => {
+                writeln!(f, "Invalid ABI: {}", error)
+            }
+            Error::EventNotFound(event) => {
+                writeln!(f, "Event not found in the ABI: {}", event)
+            }
+            Error::AmbiguousEvent(event, signatures) => {
+                writeln!(
+                    f,
+                    "Event {} is overloaded, use one of its signatures: {}",
+                    event,
+                    signatures.join(", ")
+                )
+            }
+            Error::InvalidEvent(error) => {
+                writeln!(f, "Invalid event in the ABI: {}", error)
+            }
+            Error::FunctionFromAddress(handler) => {
+                writeln!(
+                    f,
+                    "Transaction handler {} filters by function, which requires the `to` direction",
+                    handler
+                )
+            }
+            Error::Config(error) => {
+                writeln!(f, "Invalid config: {}", error)
+            }
         }
     }
 }
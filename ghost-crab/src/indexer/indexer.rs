@@ -1,10 +1,20 @@
-use super::rpc_manager::{Provider, RPCManager};
+use super::builder::IndexerBuilder;
+use super::rpc_manager::{EffectiveRate, Provider, RPCManager};
 use crate::block_handler::{process_blocks, BlockHandlerInstance, ProcessBlocksInput};
+use crate::dynamic_handler::{self, DynamicEvent, DynamicEventHandlerInstance};
 use crate::event_handler::{process_events, EventHandlerInstance, ProcessEventsInput};
+use crate::store::Store;
+use crate::transaction_handler::{
+    process_transactions, ProcessTransactionsInput, TransactionHandlerInstance,
+};
 
 use alloy::primitives::Address;
-use ghost_crab_common::config::{self, Config, ConfigError};
+use ghost_crab_common::config::{self, Config, ConfigError, TransactionDirection};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
 use tokio::sync::mpsc::{self, Receiver};
+use tokio::task::JoinSet;
 
 use super::error::{Error, Result};
 use super::templates::{Template, TemplateManager};
@@ -13,25 +23,47 @@ pub struct Indexer {
     handlers: Vec<ProcessEventsInput>,
     rx: Receiver<Template>,
     block_handlers: Vec<ProcessBlocksInput>,
+    transaction_handlers: Vec<ProcessTransactionsInput>,
     templates: TemplateManager,
+    template_handlers: HashMap<String, EventHandlerInstance>,
     rpc_manager: RPCManager,
+    store: Option<Store>,
     config: Config,
 }
 
 impl Indexer {
+    /// Creates an indexer with the config file set with `GHOST_CRAB_CONFIG`,
+    /// or `config.json` in the current directory.
     pub fn new() -> core::result::Result<Indexer, ConfigError> {
-        let (tx, rx) = mpsc::channel::<Template>(100);
-
         let config = config::load()?;
+        Ok(Indexer::with_config(config))
+    }
+
+    /// Creates an indexer with the config file at the path.
+    pub fn from_config_path(path: impl AsRef<Path>) -> core::result::Result<Indexer, ConfigError> {
+        let config = config::load_from(path)?;
+        Ok(Indexer::with_config(config))
+    }
+
+    /// Returns a builder to create an indexer with a config built in code.
+    pub fn builder() -> IndexerBuilder {
+        IndexerBuilder::new()
+    }
+
+    pub(crate) fn with_config(config: Config) -> Indexer {
+        let (tx, rx) = mpsc::channel::<Template>(100);
 
-        Ok(Indexer {
+        Indexer {
             config,
             handlers: Vec::new(),
             block_handlers: Vec::new(),
+            transaction_handlers: Vec::new(),
             templates: TemplateManager::new(tx),
+            template_handlers: HashMap::new(),
             rpc_manager: RPCManager::new(),
+            store: None,
             rx,
-        })
+        }
     }
 
     pub async fn load_event_handler(&mut self, handler: EventHandlerInstance) -> Result<()> {
@@ -41,7 +73,17 @@ impl Indexer {
             .remove(&handler.name())
             .ok_or(Error::NotFound(handler.name()))?;
 
+        self.push_event_handler(handler, event_config).await
+    }
+
+    async fn push_event_handler(
+        &mut self,
+        handler: EventHandlerInstance,
+        event_config: config::DataSource,
+    ) -> Result<()> {
         let provider = self.get_provider(&event_config.network).await?;
+        let store = self.get_store().await?;
+        let head = self.rpc_manager.head(&event_config.network);
 
         let address = str::parse::<Address>(&event_config.address)
             .map_err(|error| Error::InvalidAddress(error))?;
@@ -53,12 +95,50 @@ impl Indexer {
             handler,
             templates: self.templates.clone(),
             provider,
+            store,
+            head,
             execution_mode: event_config.execution_mode.unwrap_or(config::ExecutionMode::Parallel),
+            concurrency: event_config.concurrency,
         });
 
         Ok(())
     }
 
+    /// Loads a handler of an event of a data source, whose logs are decoded at
+    /// runtime with the ABI of the data source, resolving the event by name or
+    /// by signature. Several events of the same data source can be loaded.
+    pub async fn load_dynamic_event_handler(
+        &mut self,
+        source: &str,
+        event: &str,
+        handler: DynamicEventHandlerInstance,
+    ) -> Result<()> {
+        let event_config = self
+            .config
+            .data_sources
+            .get(source)
+            .cloned()
+            .ok_or(Error::NotFound(source.to_string()))?;
+
+        let abi = dynamic_handler::load_abi(&event_config.abi)?;
+        let event = dynamic_handler::find_event(&abi, event)?;
+        let handler = DynamicEvent::new(source, event, handler)?;
+
+        self.push_event_handler(Arc::new(Box::new(handler)), event_config).await
+    }
+
+    /// Loads the handler of a template, to start again the sources started
+    /// from the template before a restart, whose parents won't start them.
+    pub async fn load_template_handler(&mut self, handler: EventHandlerInstance) -> Result<()> {
+        if !self.config.templates.contains_key(&handler.name()) {
+            return Err(Error::NotFound(handler.name()));
+        }
+
+        self.template_handlers.insert(handler.name(), handler);
+
+        Ok(())
+    }
+
     pub async fn load_block_handler(&mut self, handler: BlockHandlerInstance) -> Result<()> {
         let block_config = self
             .config
@@ -67,17 +147,64 @@ impl Indexer {
             .ok_or(Error::NotFound(handler.name()))?;
 
         let provider = self.get_provider(&block_config.network).await?;
+        let store = self.get_store().await?;
+        let head = self.rpc_manager.head(&block_config.network);
 
         self.block_handlers.push(ProcessBlocksInput {
             handler,
             templates: self.templates.clone(),
             provider,
+            store,
+            head,
             config: block_config,
         });
 
         Ok(())
     }
 
+    pub async fn load_transaction_handler(
+        &mut self,
+        handler: TransactionHandlerInstance,
+    ) -> Result<()> {
+        let transaction_config = self
+            .config
+            .transaction_handlers
+            .remove(&handler.name())
+            .ok_or(Error::NotFound(handler.name()))?;
+
+        let provider = self.get_provider(&transaction_config.network).await?;
+        let store = self.get_store().await?;
+        let head = self.rpc_manager.head(&transaction_config.network);
+
+        let address =
+            str::parse::<Address>(&transaction_config.address).map_err(Error::InvalidAddress)?;
+
+        // The calls sent from the address go to other contracts, whose functions are not in the ABI
+        if transaction_config.direction == TransactionDirection::From
+            && handler.function_selector().is_some()
+        {
+            return Err(Error::FunctionFromAddress(handler.name()));
+        }
+
+        self.transaction_handlers.push(ProcessTransactionsInput {
+            start_block: transaction_config.start_block,
+            address,
+            direction: transaction_config.direction,
+            step: 10_000,
+            handler,
+            templates: self.templates.clone(),
+            provider,
+            store,
+            head,
+            execution_mode: transaction_config
+                .execution_mode
+                .unwrap_or(config::ExecutionMode::Parallel),
+            concurrency: transaction_config.concurrency,
+        });
+
+        Ok(())
+    }
+
     async fn get_provider(&mut self, network_name: &str) -> Result<Provider> {
         let network = self
             .config
@@ -87,61 +214,191 @@ impl Indexer {
 
         let provider = self
             .rpc_manager
-            .get_or_create(
-                network_name.to_string(),
-                network.rpc_url.clone(),
-                network.requests_per_second,
-            )
+            .get_or_create(network_name.to_string(), network, &self.config.cache)
             .await?;
 
         Ok(provider)
     }
 
+    async fn get_store(&mut self) -> Result<Store> {
+        if let Some(store) = &self.store {
+            return Ok(store.clone());
+        }
+
+        let database = config::database_url(&self.config).map_err(Error::Config)?;
+        let store = Store::connect(database.as_deref()).await.map_err(Error::Store)?;
+        self.store = Some(store.clone());
+
+        Ok(store)
+    }
+
+    #[cfg(feature = "graphql")]
+    async fn start_graphql_server(&mut self) -> Result<()> {
+        let Some(graphql) = self.config.graphql.clone() else {
+            return Ok(());
+        };
+
+        let store = self.get_store().await?;
+
+        // The queries would all fail without a database
+        if !store.is_configured() {
+            return Err(Error::GraphQL(Box::new(crate::store::StoreError::NotConfigured)));
+        }
+
+        let entities = match &self.config.schema {
+            Some(path) => Some(
+                ghost_crab_common::schema::load(path)
+                    .map_err(|error| Error::GraphQL(Box::new(error)))?,
+            ),
+            None => None,
+        };
+
+        let schema = crate::graphql::build_schema(store, entities.as_ref())
+            .map_err(|error| Error::GraphQL(Box::new(error)))?;
+
+        tokio::spawn(async move {
+            if let Err(error) = crate::graphql::serve(schema, &graphql.host, graphql.port).await {
+                eprintln!("Error serving GraphQL: {error}");
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Returns a handle to monitor the rate currently allowed by the rate
+    /// limit of a network, once a handler of the network is loaded.
+    pub fn effective_rate(&self, network: &str) -> Option<EffectiveRate> {
+        self.rpc_manager.effective_rate(network)
+    }
+
+    /// Spawns the source of a started template, unless it is already running.
+    async fn start_template(
+        &mut self,
+        template: Template,
+        started: &mut HashSet<(String, Address)>,
+        sources: &mut JoinSet<()>,
+    ) -> Result<()> {
+        if !started.insert((template.handler.name(), template.address)) {
+            return Ok(());
+        }
+
+        let config = self
+            .config
+            .templates
+            .get(&template.handler.name())
+            .ok_or(Error::NotFound(template.handler.name()))?;
+
+        let execution_mode = config.execution_mode.unwrap_or(config::ExecutionMode::Parallel);
+        let network = config.network.clone();
+        let concurrency = config.concurrency.clone();
+        let provider = self.get_provider(&network).await?;
+        let store = self.get_store().await?;
+        let head = self.rpc_manager.head(&network);
+
+        let handler = ProcessEventsInput {
+            start_block: template.start_block,
+            address: template.address,
+            step: 10_000,
+            handler: template.handler,
+            templates: self.templates.clone(),
+            provider,
+            store,
+            head,
+            execution_mode,
+            concurrency,
+        };
+
+        sources.spawn(async move {
+            if let Err(error) = process_events(handler).await {
+                eprintln!("Error processing logs for handler: {error}");
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Runs the sources until they are stopped. Running from the cache, in the
+    /// offline and replay modes, returns once every source reached the head.
     pub async fn start(mut self) -> Result<()> {
+        #[cfg(feature = "graphql")]
+        self.start_graphql_server().await?;
+
+        let mut started = HashSet::new();
+        let mut sources = JoinSet::new();
+
+        // The parents of the templates started before a restart are past the blocks starting them
+        let store = self.get_store().await?;
+
+        for template in store.templates().await.map_err(Error::Store)? {
+            // Indexers upgraded without loading the template handlers keep running as before
+            let Some(handler) = self.template_handlers.get(&template.name).cloned() else {
+                eprintln!(
+                    "[{}] Not starting the source of {}, load the handler of the template with load_template_handler",
+                    template.name, template.address
+                );
+
+                continue;
+            };
+
+            let template =
+                Template { start_block: template.start_block, address: template.address, handler };
+
+            self.start_template(template, &mut started, &mut sources).await?;
+        }
+
         for block_handler in self.block_handlers.clone() {
-            tokio::spawn(async move {
+            sources.spawn(async move {
                 if let Err(error) = process_blocks(block_handler).await {
-                    println!("Error processing logs for block handler: {error}");
+                    eprintln!("Error processing logs for block handler: {error}");
                 }
             });
         }
 
         for handler in self.handlers.clone() {
-            tokio::spawn(async move {
+            sources.spawn(async move {
                 if let Err(error) = process_events(handler).await {
-                    println!("Error processing logs for handler: {error}");
+                    eprintln!("Error processing logs for handler: {error}");
                 }
             });
         }
 
-        // For dynamic sources (Templates)
-        while let Some(template) = self.rx.recv().await {
-            let config = self
-                .config
-                .templates
-                .get(&template.handler.name())
-                .ok_or(Error::NotFound(template.handler.name()))?;
-
-            let execution_mode = config.execution_mode.unwrap_or(config::ExecutionMode::Parallel);
-            let provider = self.get_provider(&config.network.clone()).await?;
-
-            let handler = ProcessEventsInput {
-                start_block: template.start_block,
-                address: template.address,
-                step: 10_000,
-                handler: template.handler,
-                templates: self.templates.clone(),
-                provider,
-                execution_mode,
-            };
-
-            tokio::spawn(async move {
-                if let Err(error) = process_events(handler).await {
-                    println!("Error processing logs for handler: {error}");
+        for transaction_handler in self.transaction_handlers.clone() {
+            sources.spawn(async move {
+                if let Err(error) = process_transactions(transaction_handler).await {
+                    eprintln!("Error processing transactions for transaction handler: {error}");
                 }
             });
         }
 
+        let bounded = matches!(
+            self.config.cache.mode,
+            config::CacheMode::Offline | config::CacheMode::Replay
+        );
+
+        loop {
+            // A template is sent before the source starting it finishes
+            if bounded && sources.is_empty() {
+                match self.rx.try_recv() {
+                    Ok(template) => {
+                        self.start_template(template, &mut started, &mut sources).await?
+                    }
+                    Err(_) => break,
+                }
+
+                continue;
+            }
+
+            tokio::select! {
+                // For dynamic sources (Templates)
+                Some(template) = self.rx.recv() => {
+                    self.start_template(template, &mut started, &mut sources).await?;
+                }
+                Some(_) = sources.join_next() => {}
+            }
+        }
+
+        self.rpc_manager.report_cache_misses();
+
         Ok(())
     }
 }
@@ -1,4 +1,5 @@
-mod cache;
+pub mod builder;
+pub mod cache;
 pub mod error;
 pub mod indexer;
 pub mod rpc_manager;
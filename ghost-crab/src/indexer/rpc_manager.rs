@@ -1,45 +1,135 @@
-use super::cache::load_cache;
+use super::cache::{cached_head, evict, load_cache, CacheBackendInstance};
 use super::error::{Error, Result};
-use crate::layers::cache_layer::CacheLayer;
-use crate::layers::cache_layer::CacheService;
-use crate::layers::rate_limit_layer::RateLimit;
-use crate::layers::rate_limit_layer::RateLimitLayer;
+use crate::layers::cache_layer::{CacheLayer, CacheMisses, ReadTracker};
+use crate::layers::coalesce_layer::CoalesceLayer;
+use crate::layers::rate_limit_backend::FileBackend;
+pub use crate::layers::rate_limit_layer::EffectiveRate;
+use crate::layers::rate_limit_layer::{Rate, RateLimitLayer};
 use alloy::providers::ProviderBuilder;
 use alloy::providers::RootProvider;
 use alloy::rpc::client::ClientBuilder;
 use alloy::transports::http::reqwest::Url;
-use alloy::transports::http::{Client, Http};
+use alloy::transports::BoxTransport;
+use ghost_crab_common::config::{CacheConfig, CacheMode, NetworkConfig};
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-pub type Provider = RootProvider<CacheService<RateLimit<Http<Client>>>>;
+pub type Provider = RootProvider<BoxTransport>;
 
 pub struct RPCManager {
     rpcs: HashMap<String, Provider>,
+    heads: HashMap<String, u64>,
+    rates: HashMap<String, EffectiveRate>,
+    misses: HashMap<String, CacheMisses>,
 }
 
 impl RPCManager {
     pub fn new() -> Self {
-        RPCManager { rpcs: HashMap::new() }
+        RPCManager {
+            rpcs: HashMap::new(),
+            heads: HashMap::new(),
+            rates: HashMap::new(),
+            misses: HashMap::new(),
+        }
+    }
+
+    /// Returns the highest block cached for the network, in the offline and
+    /// replay modes.
+    pub fn head(&self, network: &str) -> Option<u64> {
+        self.heads.get(network).copied()
+    }
+
+    /// Returns the rate currently allowed by the rate limit of the network.
+    pub fn effective_rate(&self, network: &str) -> Option<EffectiveRate> {
+        self.rates.get(network).cloned()
+    }
+
+    /// Prints the requests that were missing from the cache of each network,
+    /// in offline mode.
+    pub fn report_cache_misses(&self) {
+        let mut networks: Vec<_> = self.misses.iter().collect();
+        networks.sort_by_key(|(network, _)| *network);
+
+        for (network, misses) in networks {
+            let misses = misses.lock().unwrap();
+
+            if misses.is_empty() {
+                continue;
+            }
+
+            eprintln!("[{}] {} requests missing from the cache:", network, misses.len());
+
+            for miss in misses.iter() {
+                eprintln!("  {}", miss);
+            }
+        }
     }
 
     pub async fn get_or_create(
         &mut self,
         network: String,
-        rpc_url: String,
-        rate_limit: u64,
+        network_config: &NetworkConfig,
+        cache_config: &CacheConfig,
     ) -> Result<Provider> {
-        if let Some(provider) = self.rpcs.get(&rpc_url) {
+        let rpc_url = &network_config.rpc_url;
+
+        if let Some(provider) = self.rpcs.get(rpc_url) {
             return Ok(provider.clone());
         }
 
-        let url = Url::parse(&rpc_url).map_err(|e| Error::InvalidRpcUrl(Box::new(e)))?;
-        let cache = load_cache(&network)?;
+        let url = Url::parse(rpc_url).map_err(|e| Error::InvalidRpcUrl(Box::new(e)))?;
+        let cache = load_cache(&network, cache_config)?;
+
+        if matches!(cache_config.mode, CacheMode::Offline | CacheMode::Replay) {
+            let head = cached_head(cache.as_ref()).ok_or(Error::EmptyCache(network.clone()))?;
+            self.heads.insert(network.clone(), head);
+        }
 
-        let cache_layer = CacheLayer::new(cache);
-        let rate_limit_layer = RateLimitLayer::new(rate_limit, Duration::from_secs(1));
+        let cache_layer = CacheLayer::new(Arc::clone(&cache), cache_config.clone());
 
-        let client = ClientBuilder::default().layer(cache_layer).layer(rate_limit_layer).http(url);
+        if cache_config.mode == CacheMode::Live
+            && (cache_config.max_size_mb.is_some() || !cache_config.retention.is_empty())
+        {
+            spawn_eviction(network.clone(), cache, cache_layer.reads(), cache_config.clone());
+        }
+
+        if cache_config.mode == CacheMode::Offline {
+            self.misses.insert(network.clone(), cache_layer.misses());
+        }
+        let coalesce_layer = CoalesceLayer::new();
+        let rate = Rate::new(
+            network_config.requests_per_second,
+            network_config.burst.unwrap_or(network_config.requests_per_second),
+            network_config.method_costs.clone(),
+        )
+        .adaptive(network_config.adaptive);
+
+        let rate_limit_layer = match &network_config.shared_rate_limit {
+            Some(shared) => {
+                let directory = match &shared.path {
+                    Some(path) => PathBuf::from(path),
+                    None => std::env::temp_dir().join("ghost-crab"),
+                };
+
+                let key = shared.key.as_deref().unwrap_or(&network);
+                let backend = FileBackend::open(&directory, key, &rate).map_err(Error::Io)?;
+
+                RateLimitLayer::with_backend(network.clone(), rate, Arc::new(backend))
+            }
+            None => RateLimitLayer::new(network.clone(), rate),
+        };
+
+        self.rates.insert(network, rate_limit_layer.effective_rate());
+
+        // Identical requests missing from the cache are coalesced before reaching the RPC
+        let client = ClientBuilder::default()
+            .layer(cache_layer)
+            .layer(coalesce_layer)
+            .layer(rate_limit_layer)
+            .http(url)
+            .boxed();
         let provider = ProviderBuilder::new().on_client(client);
 
         self.rpcs.insert(rpc_url.clone(), provider.clone());
@@ -47,3 +137,36 @@ impl RPCManager {
         Ok(provider)
     }
 }
+
+/// Periodically evicts the entries of the cache that exceed the size or the retention policies.
+fn spawn_eviction(
+    network: String,
+    cache: CacheBackendInstance,
+    reads: ReadTracker,
+    config: CacheConfig,
+) {
+    tokio::spawn(async move {
+        loop {
+            let cache = Arc::clone(&cache);
+            let reads = reads.clone();
+            let config = config.clone();
+
+            let evicted = tokio::task::spawn_blocking(move || {
+                reads.flush(cache.as_ref()).map_err(|error| error.to_string())?;
+                evict(cache.as_ref(), &config).map_err(|error| error.to_string())
+            })
+            .await;
+
+            match evicted {
+                Ok(Ok(0)) => {}
+                Ok(Ok(evicted)) => println!("[{}] Evicted {} cache entries", network, evicted),
+                Ok(Err(error)) => {
+                    eprintln!("[{}] Error evicting cache entries: {}", network, error)
+                }
+                Err(error) => eprintln!("[{}] Error evicting cache entries: {}", network, error),
+            }
+
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    });
+}
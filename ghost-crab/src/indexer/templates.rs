@@ -3,6 +3,7 @@ use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::Sender;
 
 use crate::event_handler::EventHandlerInstance;
+use crate::store::StoreTransaction;
 
 pub struct Template {
     pub start_block: u64,
@@ -13,14 +14,26 @@ pub struct Template {
 #[derive(Clone)]
 pub struct TemplateManager {
     tx: Sender<Template>,
+    /// Transaction of the range of the handler, where the started templates
+    /// are saved so they are started again after a restart.
+    store: Option<StoreTransaction>,
 }
 
 impl TemplateManager {
     pub fn new(tx: Sender<Template>) -> TemplateManager {
-        TemplateManager { tx }
+        TemplateManager { tx, store: None }
+    }
+
+    /// Returns a handle saving the started templates in the transaction.
+    pub(crate) fn with_store(&self, store: StoreTransaction) -> TemplateManager {
+        TemplateManager { tx: self.tx.clone(), store: Some(store) }
     }
 
     pub async fn start(&self, template: Template) -> Result<(), SendError<Template>> {
+        if let Some(store) = &self.store {
+            store.start_template(&template.handler.name(), template.address, template.start_block);
+        }
+
         self.tx.send(template).await
     }
 }
@@ -9,14 +9,20 @@ pub struct LatestBlockManager {
     cache_duration: Duration,
     block_number: Option<u64>,
     last_fetch: Instant,
+    head: Option<u64>,
 }
 
 impl LatestBlockManager {
-    pub fn new(provider: Provider, cache_duration: Duration) -> Self {
-        Self { provider, cache_duration, block_number: None, last_fetch: Instant::now() }
+    /// When `head` is set, it is used as the latest block instead of fetching it.
+    pub fn new(provider: Provider, cache_duration: Duration, head: Option<u64>) -> Self {
+        Self { provider, cache_duration, block_number: None, last_fetch: Instant::now(), head }
     }
 
     pub async fn get(&mut self) -> Result<u64, TransportError> {
+        if let Some(head) = self.head {
+            return Ok(head);
+        }
+
         if let Some(block_number) = self.block_number {
             if self.last_fetch.elapsed() < self.cache_duration {
                 return Ok(block_number);
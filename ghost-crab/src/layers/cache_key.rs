@@ -0,0 +1,131 @@
+use serde_json::{Map, Value};
+
+/// Key under which the version of the cache keys is stored.
+pub const VERSION_KEY: &str = "ghost_crab:version";
+pub const VERSION: &str = "2";
+
+/// Prefix of the keys where the last read of each entry is stored.
+pub const ACCESS_PREFIX: &str = "ghost_crab:access:";
+
+/// Returns the key where the last read of an entry is stored.
+pub fn access_key(key: &[u8]) -> Vec<u8> {
+    [ACCESS_PREFIX.as_bytes(), key].concat()
+}
+
+/// Returns the params with the object keys sorted and the hex strings in
+/// lowercase, so equivalent requests are serialized the same way.
+pub fn canonical_params(params: &Value) -> Value {
+    match params {
+        Value::Object(object) => {
+            let mut entries: Vec<(&String, &Value)> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+
+            let mut canonical = Map::new();
+
+            for (key, value) in entries {
+                canonical.insert(key.clone(), canonical_params(value));
+            }
+
+            Value::Object(canonical)
+        }
+        Value::Array(values) => Value::Array(values.iter().map(canonical_params).collect()),
+        Value::String(string) if string.starts_with("0x") => Value::String(string.to_lowercase()),
+        value => value.clone(),
+    }
+}
+
+fn parse_block_number(value: &Value) -> Option<u64> {
+    u64::from_str_radix(value.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+/// Returns the block a request is scoped to, if any.
+pub fn request_block_number(method: &str, params: &Value) -> Option<u64> {
+    match method {
+        "eth_getLogs" | "trace_filter" => parse_block_number(params.get(0)?.get("toBlock")?),
+        "eth_getBlockByNumber" => parse_block_number(params.get(0)?),
+        "eth_call" => parse_block_number(params.get(1)?),
+        _ => None,
+    }
+}
+
+/// Builds the key of a request as `{method}:{block_number}:{hash}`, where the
+/// hash is the blake3 hash of the canonical params. The block number is hex
+/// encoded with a fixed width, so the keys of a method are sorted by block.
+pub fn cache_key(method: &str, params: &Value) -> String {
+    let canonical = canonical_params(params).to_string();
+    let hash = blake3::hash(canonical.as_bytes());
+    let block_number = request_block_number(method, params).unwrap_or_default();
+
+    format!("{method}:{block_number:016x}:{hash}")
+}
+
+/// Returns the method and the block number of a cache key.
+pub fn parse_cache_key(key: &[u8]) -> Option<(&str, u64)> {
+    let key = std::str::from_utf8(key).ok()?;
+    let mut parts = key.splitn(3, ':');
+
+    let method = parts.next()?;
+    let block_number = u64::from_str_radix(parts.next()?, 16).ok()?;
+    parts.next()?;
+
+    Some((method, block_number))
+}
+
+/// Returns the key of a request cached before the keys were hashed, where the
+/// key was the serialized request with the id set to zero.
+pub fn migrate_key(key: &[u8]) -> Option<String> {
+    let key = std::str::from_utf8(key).ok()?;
+
+    // Non-cacheable requests recorded as fixtures end with their occurrence
+    let (request, occurrence) = match key.rsplit_once('#') {
+        Some((request, occurrence)) if occurrence.parse::<u64>().is_ok() => {
+            (request, Some(occurrence))
+        }
+        _ => (key, None),
+    };
+
+    let request: Value = serde_json::from_str(request).ok()?;
+    let method = request.get("method")?.as_str()?;
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let key = cache_key(method, &params);
+
+    match occurrence {
+        Some(occurrence) => Some(format!("{key}#{occurrence}")),
+        None => Some(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hashes_equivalent_params_the_same_way() {
+        let params = json!([{ "toBlock": "0xA", "address": "0xAbC", "fromBlock": "0x1" }]);
+        let equivalent = json!([{ "address": "0xabc", "fromBlock": "0x1", "toBlock": "0xa" }]);
+
+        assert_eq!(cache_key("eth_getLogs", &params), cache_key("eth_getLogs", &equivalent));
+        assert_ne!(cache_key("eth_getLogs", &params), cache_key("trace_filter", &params));
+    }
+
+    #[test]
+    fn parses_the_block_number_of_the_key() {
+        let key = cache_key("eth_call", &json!([{ "to": "0x01" }, "0xff"]));
+
+        assert!(key.starts_with("eth_call:00000000000000ff:"));
+        assert_eq!(parse_cache_key(key.as_bytes()), Some(("eth_call", 255)));
+        assert_eq!(parse_cache_key(b"eth_call:ff"), None);
+    }
+
+    #[test]
+    fn migrates_the_serialized_requests() {
+        let params = json!(["0x10", false]);
+        let request = json!({ "id": 0, "jsonrpc": "2.0", "method": "eth_getBlockByNumber", "params": params });
+        let key = cache_key("eth_getBlockByNumber", &params);
+
+        assert_eq!(migrate_key(request.to_string().as_bytes()), Some(key.clone()));
+        assert_eq!(migrate_key(format!("{request}#2").as_bytes()), Some(format!("{key}#2")));
+    }
+}
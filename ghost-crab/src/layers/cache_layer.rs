@@ -1,25 +1,85 @@
+use super::cache_key::{access_key, cache_key};
+use crate::indexer::cache::{CacheBackend, CacheBackendInstance, CacheError, CacheWrite};
+use crate::multicall::{call_params, decode_aggregate3, IMulticall3, MULTICALL3_ADDRESS};
+use alloy::primitives::Bytes;
 use alloy::rpc::json_rpc::{
-    Id, RequestPacket, Response, ResponsePacket, ResponsePayload, SerializedRequest,
+    Id, Request, RequestPacket, Response, ResponsePacket, ResponsePayload, SerializedRequest,
 };
+use alloy::sol_types::SolCall;
 use alloy::transports::{RpcError, TransportError, TransportErrorKind};
-use rocksdb::DB;
+use ghost_crab_common::config::{CacheConfig, CacheMode, EvictionPolicy};
 use serde_json::value::RawValue;
+use serde_json::Value;
 use std::{
-    fmt::Debug,
+    collections::{BTreeSet, HashMap},
+    fmt::{self, Debug},
     future::Future,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tower::{Layer, Service};
 
+/// A request missing from the cache while offline.
+#[derive(Debug)]
+pub struct CacheMiss {
+    pub method: String,
+    pub params: Value,
+}
+
+impl fmt::Display for CacheMiss {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request not found in the cache while offline: {} {}", self.method, self.params)
+    }
+}
+
+impl std::error::Error for CacheMiss {}
+
+/// Returns whether the error comes from a request missing from the cache,
+/// including when a handler returned it.
+pub(crate) fn is_cache_miss(error: &TransportError) -> bool {
+    match error {
+        RpcError::Transport(TransportErrorKind::Custom(error)) => error.is::<CacheMiss>(),
+        RpcError::LocalUsageError(error) => {
+            error.is::<CacheMiss>()
+                || error.downcast_ref::<TransportError>().is_some_and(is_cache_miss)
+        }
+        _ => false,
+    }
+}
+
+/// The method and params of the requests missing from the cache while offline.
+pub type CacheMisses = Arc<Mutex<BTreeSet<String>>>;
+
 pub struct CacheLayer {
-    db: Arc<DB>,
+    db: CacheBackendInstance,
+    config: Arc<CacheConfig>,
+    occurrences: Arc<Mutex<HashMap<String, u64>>>,
+    misses: CacheMisses,
+    reads: ReadTracker,
 }
 
 impl CacheLayer {
-    pub fn new(db: DB) -> Self {
-        Self { db: Arc::new(db) }
+    pub fn new(db: CacheBackendInstance, config: CacheConfig) -> Self {
+        Self {
+            db,
+            reads: ReadTracker::new(&config),
+            config: Arc::new(config),
+            occurrences: Arc::new(Mutex::new(HashMap::new())),
+            misses: CacheMisses::default(),
+        }
+    }
+
+    /// Returns the reads not written to the cache yet, flushed before evicting.
+    pub fn reads(&self) -> ReadTracker {
+        self.reads.clone()
+    }
+
+    /// Returns the requests missing from the cache so far, to report them
+    /// once the run stops.
+    pub fn misses(&self) -> CacheMisses {
+        Arc::clone(&self.misses)
     }
 }
 
@@ -27,24 +87,108 @@ impl<S> Layer<S> for CacheLayer {
     type Service = CacheService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        CacheService { inner, db: Arc::clone(&self.db) }
+        CacheService {
+            inner,
+            db: Arc::clone(&self.db),
+            config: Arc::clone(&self.config),
+            occurrences: Arc::clone(&self.occurrences),
+            misses: Arc::clone(&self.misses),
+            reads: self.reads.clone(),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct CacheService<S> {
     inner: S,
-    db: Arc<DB>,
+    db: CacheBackendInstance,
+    config: Arc<CacheConfig>,
+    /// Number of times each non-cacheable request was recorded or replayed,
+    /// as their responses change over time (e.g. `eth_blockNumber`).
+    occurrences: Arc<Mutex<HashMap<String, u64>>>,
+    misses: CacheMisses,
+    reads: ReadTracker,
 }
 
 impl<S> CacheService<S> {
-    fn convert_to_response(
+    /// Returns the key of the next occurrence of a non-cacheable request. When
+    /// replaying past the recorded occurrences, the last one is served again.
+    fn fixture_key(&self, request_key: String) -> String {
+        let mut occurrences = self.occurrences.lock().unwrap();
+        let occurrence = occurrences.entry(request_key.clone()).or_default();
+        let key = format!("{request_key}#{occurrence}");
+
+        if self.config.mode == CacheMode::Replay && *occurrence > 0 {
+            if let Ok(None) = self.db.get(key.as_bytes()) {
+                return format!("{request_key}#{}", *occurrence - 1);
+            }
+        }
+
+        *occurrence += 1;
+        key
+    }
+
+    fn missing_fixture(
+        &self,
+        raw_request: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponsePacket, TransportError>> + Send>> {
+        let error = TransportErrorKind::custom_str(&format!(
+            "Request not found in the recorded fixtures: {raw_request}"
+        ));
+
+        Box::pin(async move { Err(error) })
+    }
+
+    /// Records the missing requests, which are reported together once the
+    /// run stops, and fails with a [`CacheMiss`].
+    fn missing_cache_entry(
         &self,
-        raw_response: Vec<u8>,
+        requests: Vec<(&str, Value)>,
     ) -> Pin<Box<dyn Future<Output = Result<ResponsePacket, TransportError>> + Send>> {
-        let raw_value = String::from_utf8(raw_response).unwrap();
-        let raw_value = RawValue::from_string(raw_value).unwrap();
+        let mut misses = self.misses.lock().unwrap();
+
+        for (method, params) in &requests {
+            misses.insert(format!("{method} {params}"));
+        }
 
+        let (method, params) = requests.into_iter().next().unwrap_or_default();
+        let error = TransportErrorKind::custom(CacheMiss { method: method.to_string(), params });
+
+        Box::pin(async move { Err(error) })
+    }
+
+    /// Returns the cached response of a request. Invalid entries are evicted,
+    /// so the request is sent again.
+    fn lookup(&self, key: &str, skip_missing: bool) -> Result<Option<Box<RawValue>>, CacheError> {
+        let raw_data = match self.db.get(key.as_bytes())? {
+            Some(raw_data) => raw_data,
+            None => return Ok(None),
+        };
+
+        match parse_cached_response(raw_data, skip_missing) {
+            Some(raw_value) => {
+                self.reads.record(self.db.as_ref(), key);
+                Ok(Some(raw_value))
+            }
+            None => {
+                eprintln!("Evicting invalid cache entry: {key}");
+                self.db.write(vec![CacheWrite::delete(key.as_bytes())])?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn storage_error(
+        &self,
+        error: CacheError,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponsePacket, TransportError>> + Send>> {
+        Box::pin(async move { Err(TransportError::local_usage(error)) })
+    }
+
+    fn convert_to_response(
+        &self,
+        raw_value: Box<RawValue>,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponsePacket, TransportError>> + Send>> {
         let response_payload: ResponsePayload<Box<RawValue>, Box<RawValue>>;
         response_payload = ResponsePayload::Success(raw_value);
 
@@ -62,6 +206,224 @@ impl<S> CacheService<S> {
     }
 }
 
+impl<S> CacheService<S>
+where
+    S: Service<RequestPacket, Response = ResponsePacket, Error = TransportError>,
+    S::Future: Send + 'static,
+{
+    /// Serves the calls of an `aggregate3` sent by the multicall helper from
+    /// the cache, and sends the missing ones in a smaller `aggregate3`. Each
+    /// result is cached under the key of the same call made directly.
+    fn call_aggregate(
+        &mut self,
+        id: Id,
+        calls: Vec<IMulticall3::Call3>,
+        block: Value,
+        raw_request: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<ResponsePacket, TransportError>> + Send>> {
+        let mut keys = Vec::with_capacity(calls.len());
+        let mut results = Vec::with_capacity(calls.len());
+
+        for call in &calls {
+            let key = cache_key("eth_call", &call_params(call.target, &call.callData, &block));
+
+            let cached = match self.lookup(&key, false) {
+                Ok(cached) => cached,
+                Err(error) => return self.storage_error(error),
+            };
+
+            let return_data =
+                cached.and_then(|raw_value| serde_json::from_str(raw_value.get()).ok());
+
+            keys.push(key);
+            results.push(return_data.map(|return_data| IMulticall3::Call3Result {
+                success: true,
+                returnData: return_data,
+            }));
+        }
+
+        let missing: Vec<usize> =
+            (0..calls.len()).filter(|&index| results[index].is_none()).collect();
+
+        if missing.is_empty() {
+            return Box::pin(async move { aggregate_response(id, results) });
+        }
+
+        if self.config.mode == CacheMode::Replay {
+            return self.missing_fixture(raw_request);
+        }
+
+        if self.config.mode == CacheMode::Offline {
+            let requests = missing
+                .iter()
+                .map(|&index| {
+                    let call = &calls[index];
+                    ("eth_call", call_params(call.target, &call.callData, &block))
+                })
+                .collect();
+
+            return self.missing_cache_entry(requests);
+        }
+
+        let missing_calls = missing.iter().map(|&index| calls[index].clone()).collect();
+        let call_data =
+            Bytes::from(IMulticall3::aggregate3Call { calls: missing_calls }.abi_encode());
+        let params = call_params(MULTICALL3_ADDRESS, &call_data, &block);
+
+        let request = match Request::new("eth_call", id.clone(), params).serialize() {
+            Ok(request) => request,
+            Err(error) => {
+                return Box::pin(async move { Err(TransportError::ser_err(error)) });
+            }
+        };
+
+        let db = Arc::clone(&self.db);
+        let reads = self.reads.clone();
+        let future = self.inner.call(RequestPacket::Single(request));
+
+        Box::pin(async move {
+            let response = future.await?;
+
+            let payload = match &response {
+                ResponsePacket::Single(single) => match &single.payload {
+                    ResponsePayload::Success(payload) => payload.get(),
+                    ResponsePayload::Failure(_) => return Ok(response),
+                },
+                ResponsePacket::Batch(_) => return Ok(response),
+            };
+
+            let return_data: Bytes =
+                serde_json::from_str(payload).map_err(TransportError::local_usage)?;
+            let fetched = IMulticall3::aggregate3Call::abi_decode_returns(&return_data, true)
+                .map_err(TransportError::local_usage)?
+                .returnData;
+
+            if fetched.len() != missing.len() {
+                return Err(TransportErrorKind::custom_str(&format!(
+                    "Multicall returned {} results for {} calls",
+                    fetched.len(),
+                    missing.len()
+                )));
+            }
+
+            for (index, result) in missing.into_iter().zip(fetched) {
+                if result.success {
+                    let raw_response = serde_json::to_string(&result.returnData)
+                        .map_err(TransportError::ser_err)?;
+
+                    db.put(keys[index].as_bytes(), raw_response.as_bytes())
+                        .map_err(TransportError::local_usage)?;
+                    reads.record(db.as_ref(), &keys[index]);
+                }
+
+                results[index] = Some(result);
+            }
+
+            aggregate_response(id, results)
+        })
+    }
+}
+
+/// Builds the response of a Multicall3 `aggregate3` from the result of each call.
+fn aggregate_response(
+    id: Id,
+    results: Vec<Option<IMulticall3::Call3Result>>,
+) -> Result<ResponsePacket, TransportError> {
+    let results: Vec<IMulticall3::Call3Result> = results.into_iter().flatten().collect();
+    let return_data = Bytes::from(IMulticall3::aggregate3Call::abi_encode_returns(&(results,)));
+
+    let raw_value =
+        serde_json::value::to_raw_value(&return_data).map_err(TransportError::ser_err)?;
+
+    Ok(ResponsePacket::Single(Response { id, payload: ResponsePayload::Success(raw_value) }))
+}
+
+/// Parses a cached response, returning `None` if the entry is corrupt or
+/// holds a missing result that shouldn't have been cached.
+fn parse_cached_response(raw_data: Vec<u8>, skip_missing: bool) -> Option<Box<RawValue>> {
+    let raw_value = String::from_utf8(raw_data).ok()?;
+    let raw_value = RawValue::from_string(raw_value).ok()?;
+
+    if skip_missing && missing_result(raw_value.get()) {
+        return None;
+    }
+
+    Some(raw_value)
+}
+
+/// Returns whether the method looks up a block, a transaction or a receipt,
+/// which is `null` while the node hasn't seen it.
+fn may_be_missing(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBlockByNumber" | "eth_getTransactionByHash" | "eth_getTransactionReceipt"
+    )
+}
+
+/// Returns whether a lookup result is missing. It may change, so it is not
+/// cached. Empty results, like `[]` logs or `0x` return data, are valid.
+fn missing_result(raw_response: &str) -> bool {
+    raw_response.trim() == "null"
+}
+
+/// Number of reads buffered before their times are written to the cache.
+const READS_BATCH_SIZE: usize = 1000;
+
+/// Times of the last read of the entries, to evict the least recently used
+/// ones. They are written in batches rather than on every read.
+#[derive(Clone, Default)]
+pub struct ReadTracker {
+    enabled: bool,
+    reads: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl ReadTracker {
+    pub fn new(config: &CacheConfig) -> ReadTracker {
+        let enabled =
+            config.max_size_mb.is_some() && config.eviction == EvictionPolicy::LeastRecentlyUsed;
+
+        ReadTracker { enabled, reads: Arc::default() }
+    }
+
+    fn record(&self, db: &dyn CacheBackend, key: &str) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+
+        let full = {
+            let mut reads = self.reads.lock().unwrap();
+            reads.insert(key.to_string(), now as u64);
+            reads.len() >= READS_BATCH_SIZE
+        };
+
+        if full {
+            if let Err(error) = self.flush(db) {
+                eprintln!("Error writing the cache reads: {error}");
+            }
+        }
+    }
+
+    /// Writes the times of the reads buffered so far, e.g. before evicting.
+    pub fn flush(&self, db: &dyn CacheBackend) -> Result<(), CacheError> {
+        let reads = std::mem::take(&mut *self.reads.lock().unwrap());
+
+        if reads.is_empty() {
+            return Ok(());
+        }
+
+        let writes = reads
+            .into_iter()
+            .map(|(key, last_read)| {
+                CacheWrite::put(access_key(key.as_bytes()), last_read.to_be_bytes())
+            })
+            .collect();
+
+        db.write(writes)
+    }
+}
+
 const INVALID_WORDS: &[&[u8]] = &[b"earliest", b"latest", b"safe", b"finalized", b"pending"];
 
 #[inline]
@@ -75,8 +437,16 @@ fn contains_invalid_word(input: &[u8]) -> bool {
     false
 }
 
-fn cacheable_request(request: &SerializedRequest) -> bool {
-    if !matches!(request.method(), "eth_getBlockByNumber" | "eth_getLogs" | "eth_call") {
+pub(crate) fn cacheable_request(request: &SerializedRequest) -> bool {
+    if !matches!(
+        request.method(),
+        "eth_getBlockByNumber"
+            | "eth_getLogs"
+            | "eth_call"
+            | "eth_getTransactionByHash"
+            | "eth_getTransactionReceipt"
+            | "trace_filter"
+    ) {
         return false;
     }
 
@@ -106,31 +476,70 @@ where
 
     fn call(&mut self, request: RequestPacket) -> Self::Future {
         if let RequestPacket::Single(single) = &request {
-            if cacheable_request(single) {
+            let cacheable = cacheable_request(single)
+                && self.config.retention.get(single.method()).is_none_or(|policy| policy.cache);
+
+            if cacheable || matches!(self.config.mode, CacheMode::Record | CacheMode::Replay) {
                 let raw_request = single.serialized().get();
 
-                // Set id to zero
-                let id = single.id();
-                let id_old = format!("\"id\":{id}");
-                let id_new = "\"id\":0";
-                let raw_request = raw_request.replace(&id_old, id_new);
+                let params = match single.params() {
+                    Some(params) => match serde_json::from_str(params.get()) {
+                        Ok(params) => params,
+                        Err(_) => return Box::pin(self.inner.call(request)),
+                    },
+                    None => Value::Null,
+                };
+
+                // Fixtures are recorded as is to replay the same responses
+                let skip_missing = cacheable
+                    && matches!(self.config.mode, CacheMode::Live | CacheMode::Offline)
+                    && may_be_missing(single.method());
 
-                if let Ok(Some(raw_data)) = self.db.get(&raw_request) {
-                    return self.convert_to_response(raw_data);
+                if cacheable && single.method() == "eth_call" {
+                    if let Some((calls, block)) = decode_aggregate3(&params) {
+                        let id = single.id().clone();
+                        return self.call_aggregate(id, calls, block, raw_request);
+                    }
+                }
+
+                let key = cache_key(single.method(), &params);
+                let key = if cacheable { key } else { self.fixture_key(key) };
+
+                // Non-cacheable requests are recorded again on each run
+                if cacheable || self.config.mode == CacheMode::Replay {
+                    match self.lookup(&key, skip_missing) {
+                        Ok(Some(raw_value)) => return self.convert_to_response(raw_value),
+                        Ok(None) => {}
+                        Err(error) => return self.storage_error(error),
+                    }
+                }
+
+                if self.config.mode == CacheMode::Replay {
+                    return self.missing_fixture(raw_request);
+                }
+
+                if self.config.mode == CacheMode::Offline {
+                    return self.missing_cache_entry(vec![(single.method(), params)]);
                 }
 
                 let db = Arc::clone(&self.db);
+                let reads = self.reads.clone();
                 let future = self.inner.call(request);
 
                 return Box::pin(async move {
                     let response = future.await;
 
-                    if let Ok(response) = &response {
-                        if let ResponsePacket::Single(single) = response {
-                            if let ResponsePayload::Success(payload) = &single.payload {
-                                let raw_response = payload.get();
-                                db.put(raw_request, raw_response).unwrap();
+                    if let Ok(ResponsePacket::Single(single)) = &response {
+                        if let ResponsePayload::Success(payload) = &single.payload {
+                            let raw_response = payload.get();
+
+                            if skip_missing && missing_result(raw_response) {
+                                return response;
                             }
+
+                            db.put(key.as_bytes(), raw_response.as_bytes())
+                                .map_err(TransportError::local_usage)?;
+                            reads.record(db.as_ref(), &key);
                         }
                     }
 
@@ -139,6 +548,112 @@ where
             }
         }
 
+        if self.config.mode == CacheMode::Replay {
+            return self.missing_fixture("batch request");
+        }
+
         Box::pin(self.inner.call(request))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::cache::open_cache;
+    use crate::layers::cache_key::ACCESS_PREFIX;
+    use crate::testing::MockTransport;
+    use alloy::providers::{Provider, ProviderBuilder};
+    use alloy::rpc::client::ClientBuilder;
+    use alloy::rpc::types::eth::Filter;
+    use ghost_crab_common::config::CacheBackendKind;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn collects_the_offline_misses() {
+        let cache = open_cache(Path::new(""), CacheBackendKind::Memory).unwrap();
+        let config = CacheConfig { mode: CacheMode::Offline, ..Default::default() };
+        let layer = CacheLayer::new(cache, config);
+        let misses = layer.misses();
+
+        let transport = MockTransport::new();
+        let client = ClientBuilder::default().layer(layer).transport(transport.clone(), true);
+        let provider = ProviderBuilder::new().on_client(client);
+
+        for from_block in [10u64, 20] {
+            let filter = Filter::new().from_block(from_block).to_block(from_block + 9);
+            let error = provider.get_logs(&filter).await.unwrap_err();
+            assert!(is_cache_miss(&error));
+        }
+
+        let misses = misses.lock().unwrap();
+        assert_eq!(misses.len(), 2);
+        assert!(misses.iter().all(|miss| miss.starts_with("eth_getLogs ")));
+        assert!(transport.requests().is_empty());
+    }
+
+    #[test]
+    fn detects_misses_returned_by_handlers() {
+        let miss = CacheMiss { method: "eth_call".to_string(), params: Value::Null };
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(TransportErrorKind::custom(miss));
+
+        assert!(is_cache_miss(&TransportError::LocalUsageError(error)));
+        assert!(!is_cache_miss(&TransportErrorKind::custom_str("other")));
+    }
+
+    #[test]
+    fn only_skips_the_missing_lookups() {
+        assert!(may_be_missing("eth_getTransactionReceipt"));
+        assert!(!may_be_missing("eth_getLogs"));
+        assert!(!may_be_missing("eth_call"));
+
+        assert!(missing_result(" null"));
+
+        for result in ["[]", "{}", "\"\"", "\"0x\""] {
+            assert!(!missing_result(result));
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_the_empty_results() {
+        let cache = open_cache(Path::new(""), CacheBackendKind::Memory).unwrap();
+        let layer = CacheLayer::new(cache, CacheConfig::default());
+
+        let transport = MockTransport::new();
+        transport.push_response("eth_getLogs", serde_json::json!([]));
+        let client = ClientBuilder::default().layer(layer).transport(transport.clone(), true);
+        let provider = ProviderBuilder::new().on_client(client);
+
+        let filter = Filter::new().from_block(10u64).to_block(19u64);
+
+        for _ in 0..2 {
+            assert!(provider.get_logs(&filter).await.unwrap().is_empty());
+        }
+
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn writes_the_reads_in_batches() {
+        let cache = open_cache(Path::new(""), CacheBackendKind::Memory).unwrap();
+        let config = CacheConfig {
+            max_size_mb: Some(1),
+            eviction: EvictionPolicy::LeastRecentlyUsed,
+            ..Default::default()
+        };
+        let reads = ReadTracker::new(&config);
+        let access_count = || cache.entries(ACCESS_PREFIX.as_bytes()).count();
+
+        reads.record(cache.as_ref(), "eth_call:0000000000000001:hash");
+        assert_eq!(access_count(), 0);
+
+        reads.flush(cache.as_ref()).unwrap();
+        assert_eq!(access_count(), 1);
+
+        for index in 0..READS_BATCH_SIZE {
+            reads.record(cache.as_ref(), &format!("eth_call:0000000000000001:{index}"));
+        }
+
+        assert_eq!(access_count(), READS_BATCH_SIZE + 1);
+    }
+}
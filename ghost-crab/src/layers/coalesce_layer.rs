@@ -0,0 +1,330 @@
+use super::cache_key::cache_key;
+use super::cache_layer::cacheable_request;
+use alloy::rpc::json_rpc::{Id, RequestPacket, ResponsePacket};
+use alloy::transports::{RpcError, TransportError, TransportErrorKind};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tower::{Layer, Service};
+
+/// Result of an in-flight request, shared with the identical requests that
+/// were made while it was pending.
+type SharedResponse = Result<ResponsePacket, Arc<TransportError>>;
+
+/// Error of an in-flight request that can't be rebuilt for each of the
+/// identical requests, e.g. a transport error, returned as its source.
+#[derive(Debug, Clone)]
+pub struct SharedError(pub Arc<TransportError>);
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// Returns the error of the in-flight request to one of the identical
+/// requests. The error responses of the RPC, e.g. reverts with their data,
+/// keep their type, so a request gets the same error whether it was sent or
+/// coalesced.
+fn shared_error(error: &Arc<TransportError>) -> TransportError {
+    match error.as_ref() {
+        RpcError::ErrorResp(payload) => RpcError::ErrorResp(payload.clone()),
+        RpcError::NullResp => RpcError::NullResp,
+        RpcError::UnsupportedFeature(feature) => RpcError::UnsupportedFeature(feature),
+        _ => TransportErrorKind::custom(SharedError(Arc::clone(error))),
+    }
+}
+
+type InFlight = Arc<Mutex<HashMap<String, broadcast::Sender<SharedResponse>>>>;
+
+/// Deduplicates identical requests made concurrently, so only one of them is
+/// sent and all the callers get its response.
+#[derive(Debug, Clone, Default)]
+pub struct CoalesceLayer {
+    in_flight: InFlight,
+}
+
+impl CoalesceLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S> Layer<S> for CoalesceLayer {
+    type Service = CoalesceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CoalesceService { inner, in_flight: Arc::clone(&self.in_flight) }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CoalesceService<S> {
+    inner: S,
+    /// Requests being sent, by cache key.
+    in_flight: InFlight,
+}
+
+/// Removes the request from the in-flight requests once it completes or if it
+/// is dropped, so the next identical request is sent again.
+struct InFlightGuard {
+    in_flight: InFlight,
+    key: String,
+    sender: broadcast::Sender<SharedResponse>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if in_flight.get(&self.key).is_some_and(|sender| sender.same_channel(&self.sender)) {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+/// Returns the response with the id of the request it answers.
+fn with_id(response: ResponsePacket, id: Id) -> ResponsePacket {
+    match response {
+        ResponsePacket::Single(mut single) => {
+            single.id = id;
+            ResponsePacket::Single(single)
+        }
+        response => response,
+    }
+}
+
+impl<S> Service<RequestPacket> for CoalesceService<S>
+where
+    S: Service<RequestPacket, Response = ResponsePacket, Error = TransportError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        // Only the cacheable requests are coalesced, as the others (e.g.
+        // `eth_blockNumber`) are expected to return a newer result
+        let single = match &request {
+            RequestPacket::Single(single) if cacheable_request(single) => single,
+            _ => return Box::pin(self.inner.call(request)),
+        };
+
+        let params = match single.params() {
+            Some(params) => match serde_json::from_str(params.get()) {
+                Ok(params) => params,
+                Err(_) => return Box::pin(self.inner.call(request)),
+            },
+            None => Value::Null,
+        };
+
+        let key = cache_key(single.method(), &params);
+        let id = single.id().clone();
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if let Some(sender) = in_flight.get(&key) {
+            let mut receiver = sender.subscribe();
+            drop(in_flight);
+
+            let mut service = self.clone();
+
+            return Box::pin(async move {
+                match receiver.recv().await {
+                    Ok(Ok(response)) => Ok(with_id(response, id)),
+                    Ok(Err(error)) => Err(shared_error(&error)),
+                    // The in-flight request was dropped, e.g. its handler was
+                    // aborted, so this request is sent instead
+                    Err(RecvError::Closed | RecvError::Lagged(_)) => {
+                        std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+                        service.call(request).await
+                    }
+                }
+            });
+        }
+
+        let (sender, _) = broadcast::channel(1);
+        in_flight.insert(key.clone(), sender.clone());
+        drop(in_flight);
+
+        let guard = InFlightGuard { in_flight: Arc::clone(&self.in_flight), key, sender };
+        let future = self.inner.call(request);
+
+        Box::pin(async move {
+            // The error is shared, so this request gets it the same way as the identical ones
+            let response = future.await.map_err(Arc::new);
+
+            // The request is removed before sending the response, so the
+            // requests made after this point are sent again
+            let sender = guard.sender.clone();
+            drop(guard);
+            let _ = sender.send(response.clone());
+
+            response.map_err(|error| shared_error(&error))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::rpc::json_rpc::{ErrorPayload, Request, Response, ResponsePayload};
+    use alloy::transports::TransportFut;
+    use serde_json::value::RawValue;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::sync::Semaphore;
+
+    /// Transport answering a request once a response is released.
+    #[derive(Clone)]
+    struct GatedTransport {
+        calls: Arc<AtomicU32>,
+        responses: Arc<Semaphore>,
+        error: Option<ErrorPayload>,
+    }
+
+    impl GatedTransport {
+        fn new(error: Option<ErrorPayload>) -> Self {
+            GatedTransport {
+                calls: Arc::new(AtomicU32::new(0)),
+                responses: Arc::new(Semaphore::new(0)),
+                error,
+            }
+        }
+    }
+
+    impl Service<RequestPacket> for GatedTransport {
+        type Response = ResponsePacket;
+        type Error = TransportError;
+        type Future = TransportFut<'static>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: RequestPacket) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+
+            let id = match &request {
+                RequestPacket::Single(single) => single.id().clone(),
+                RequestPacket::Batch(_) => Id::None,
+            };
+
+            let responses = Arc::clone(&self.responses);
+            let error = self.error.clone();
+
+            Box::pin(async move {
+                responses.acquire().await.unwrap().forget();
+
+                match error {
+                    Some(error) => Err(RpcError::ErrorResp(error)),
+                    None => Ok(ResponsePacket::Single(Response {
+                        id,
+                        payload: ResponsePayload::Success(
+                            RawValue::from_string("\"0x1\"".into()).unwrap(),
+                        ),
+                    })),
+                }
+            })
+        }
+    }
+
+    fn request(id: u64) -> RequestPacket {
+        let params =
+            serde_json::json!([{ "to": "0x0000000000000000000000000000000000000001" }, "0x64"]);
+        RequestPacket::Single(Request::new("eth_call", Id::Number(id), params).serialize().unwrap())
+    }
+
+    fn response_id(response: &ResponsePacket) -> Option<Id> {
+        match response {
+            ResponsePacket::Single(single) => Some(single.id.clone()),
+            ResponsePacket::Batch(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_the_identical_requests_once() {
+        let transport = GatedTransport::new(None);
+        let mut service = CoalesceLayer::new().layer(transport.clone());
+
+        let first = service.call(request(1));
+        let second = service.call(request(2));
+        transport.responses.add_permits(1);
+
+        let (first, second) = tokio::join!(first, second);
+
+        assert_eq!(transport.calls.load(Ordering::Relaxed), 1);
+        assert_eq!(response_id(&first.unwrap()), Some(Id::Number(1)));
+        assert_eq!(response_id(&second.unwrap()), Some(Id::Number(2)));
+    }
+
+    #[tokio::test]
+    async fn shares_the_error_responses() {
+        let revert = ErrorPayload {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some(RawValue::from_string("\"0x08c379a0\"".into()).unwrap()),
+        };
+
+        let transport = GatedTransport::new(Some(revert));
+        let mut service = CoalesceLayer::new().layer(transport.clone());
+
+        let first = service.call(request(1));
+        let second = service.call(request(2));
+        transport.responses.add_permits(1);
+
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!(transport.calls.load(Ordering::Relaxed), 1);
+
+        for error in [first.unwrap_err(), second.unwrap_err()] {
+            let payload = error.as_error_resp().unwrap();
+            assert_eq!(payload.code, 3);
+            assert_eq!(payload.data.as_ref().unwrap().get(), "\"0x08c379a0\"");
+        }
+    }
+
+    #[test]
+    fn wraps_the_errors_that_cant_be_rebuilt() {
+        let error = Arc::new(TransportErrorKind::custom_str("connection reset"));
+        let shared = shared_error(&error);
+
+        assert!(matches!(shared, RpcError::Transport(TransportErrorKind::Custom(_))));
+        assert_eq!(shared.to_string(), error.to_string());
+    }
+
+    #[tokio::test]
+    async fn sends_the_request_when_the_in_flight_one_is_dropped() {
+        let transport = GatedTransport::new(None);
+        let mut service = CoalesceLayer::new().layer(transport.clone());
+
+        let first = service.call(request(1));
+        let second = tokio::spawn(service.call(request(2)));
+        drop(first);
+
+        transport.responses.add_permits(1);
+        let second = second.await.unwrap();
+
+        assert_eq!(transport.calls.load(Ordering::Relaxed), 2);
+        assert_eq!(response_id(&second.unwrap()), Some(Id::Number(2)));
+    }
+}
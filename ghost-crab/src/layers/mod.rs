@@ -1,2 +1,5 @@
+pub mod cache_key;
 pub mod cache_layer;
+pub mod coalesce_layer;
+pub mod rate_limit_backend;
 pub mod rate_limit_layer;
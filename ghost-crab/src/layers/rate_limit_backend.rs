@@ -0,0 +1,148 @@
+use super::rate_limit_layer::{Bucket, Rate};
+use core::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum RateLimitError {
+    Io(std::io::Error),
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateLimitError::Io(error) => write!(f, "IO error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// Storage of the token bucket of a rate limit, which can be shared by several
+/// processes so they respect the same budget.
+pub trait RateLimitBackend: fmt::Debug {
+    /// Applies an update to the bucket, without other updates in between.
+    fn update(&self, update: &mut dyn FnMut(&mut Bucket)) -> Result<(), RateLimitError>;
+
+    /// Whether the updates block the thread, e.g. waiting for a lock held by
+    /// another process, so they are applied on a blocking thread.
+    fn is_blocking(&self) -> bool {
+        false
+    }
+}
+
+pub type RateLimitBackendInstance = Arc<dyn RateLimitBackend + Send + Sync>;
+
+/// Bucket only known by the current process.
+#[derive(Debug)]
+pub struct LocalBackend {
+    bucket: Mutex<Bucket>,
+}
+
+impl LocalBackend {
+    pub fn new(rate: &Rate) -> Self {
+        LocalBackend { bucket: Mutex::new(Bucket::new(rate)) }
+    }
+}
+
+impl RateLimitBackend for LocalBackend {
+    fn update(&self, update: &mut dyn FnMut(&mut Bucket)) -> Result<(), RateLimitError> {
+        update(&mut self.bucket.lock().unwrap());
+        Ok(())
+    }
+}
+
+/// Bucket stored in a file, locked while it is updated, so the processes of a
+/// machine sharing the same key respect the same budget.
+#[derive(Debug)]
+pub struct FileBackend {
+    path: PathBuf,
+    initial: Bucket,
+}
+
+impl FileBackend {
+    /// Opens the bucket of the key in the directory, which is created if missing.
+    pub fn open(directory: &Path, key: &str, rate: &Rate) -> std::io::Result<Self> {
+        std::fs::create_dir_all(directory)?;
+
+        let file_name: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+
+        let path = directory.join(format!("{file_name}.json"));
+
+        Ok(FileBackend { path, initial: Bucket::new(rate) })
+    }
+
+    fn update_file(
+        &self,
+        file: &mut File,
+        update: &mut dyn FnMut(&mut Bucket),
+    ) -> std::io::Result<()> {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        // A new or unreadable bucket starts full
+        let mut bucket = serde_json::from_str(&contents).unwrap_or_else(|_| self.initial.clone());
+        update(&mut bucket);
+
+        let contents = serde_json::to_vec(&bucket)?;
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&contents)
+    }
+}
+
+impl RateLimitBackend for FileBackend {
+    fn update(&self, update: &mut dyn FnMut(&mut Bucket)) -> Result<(), RateLimitError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .map_err(RateLimitError::Io)?;
+
+        file.lock().map_err(RateLimitError::Io)?;
+        let result = self.update_file(&mut file, update);
+        let unlocked = file.unlock();
+
+        result.and(unlocked).map_err(RateLimitError::Io)
+    }
+
+    fn is_blocking(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn shares_the_budget_through_the_file() {
+        let directory =
+            std::env::temp_dir().join(format!("ghost-crab-rate-limit-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let rate = Rate::new(10, 20, HashMap::new());
+        let first = FileBackend::open(&directory, "mainnet", &rate).unwrap();
+        let second = FileBackend::open(&directory, "mainnet", &rate).unwrap();
+
+        let mut wait = Duration::ZERO;
+        first.update(&mut |bucket| wait = bucket.reserve(&rate, 20)).unwrap();
+        assert_eq!(wait, Duration::ZERO);
+
+        // The burst spent by the first backend is not available to the second one
+        second.update(&mut |bucket| wait = bucket.reserve(&rate, 20)).unwrap();
+        assert!(wait > Duration::from_secs(1));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
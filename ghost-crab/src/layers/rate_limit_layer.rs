@@ -1,32 +1,110 @@
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use super::rate_limit_backend::{LocalBackend, RateLimitBackendInstance};
+use alloy::rpc::json_rpc::{RequestPacket, Response, ResponsePacket, SerializedRequest};
+use alloy::transports::{RpcError, TransportError, TransportErrorKind};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::time::{Instant, Sleep};
 use tower::Layer;
 use tower::Service;
 
-#[derive(Debug, Copy, Clone)]
+/// Factor applied to the effective rate when the RPC rate limits a request.
+const BACKOFF_FACTOR: f64 = 0.5;
+
+/// Minimum time between two backoffs, so the requests rate limited at the
+/// same time only count once.
+const BACKOFF_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of the ceiling recovered per second without being rate limited.
+const RECOVERY_PER_SECOND: f64 = 0.05;
+
+/// Share of the ceiling the effective rate never goes below.
+const MIN_RATE: f64 = 0.05;
+
+/// Number of times an adaptive rate limit sends a rate limited request again,
+/// before returning the error.
+const MAX_RETRIES: u32 = 5;
+
+/// Compute units refilled per second, the burst that can be spent at once and
+/// the cost of each method. Unlisted methods cost one compute unit.
+#[derive(Debug, Clone)]
 pub struct Rate {
-    limit: u64,
-    period: Duration,
+    per_second: u64,
+    burst: u64,
+    costs: Arc<HashMap<String, u64>>,
+    adaptive: bool,
+}
+
+impl Rate {
+    pub fn new(per_second: u64, burst: u64, costs: HashMap<String, u64>) -> Self {
+        Rate {
+            per_second: per_second.max(1),
+            burst: burst.max(1),
+            costs: Arc::new(costs),
+            adaptive: false,
+        }
+    }
+
+    /// Uses the rate as a ceiling, backing off when the RPC rate limits the
+    /// requests and recovering gradually afterwards. The rate limited requests
+    /// are sent again once their compute units are available at the new rate.
+    pub fn adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    fn method_cost(&self, request: &SerializedRequest) -> u64 {
+        self.costs.get(request.method()).copied().unwrap_or(1)
+    }
+
+    /// Returns the compute units of a request, or the sum of the batched requests.
+    fn cost(&self, request: &RequestPacket) -> u64 {
+        match request {
+            RequestPacket::Single(single) => self.method_cost(single),
+            RequestPacket::Batch(batch) => {
+                batch.iter().map(|single| self.method_cost(single)).sum()
+            }
+        }
+    }
 }
 
-/// Enforces a rate limit on the number of requests the underlying
-/// service can handle over a period of time.
+/// Enforces a rate limit on the compute units the underlying service can
+/// spend, with a token bucket that allows bursts.
 #[derive(Debug, Clone)]
 pub struct RateLimitLayer {
+    name: String,
     rate: Rate,
+    backend: RateLimitBackendInstance,
+    effective_rate: EffectiveRate,
 }
 
 impl RateLimitLayer {
-    /// Create new rate limit layer.
-    pub fn new(limit: u64, period: Duration) -> Self {
-        let rate = Rate { limit, period };
-        RateLimitLayer { rate }
+    /// Create new rate limit layer, named after the network in the logs.
+    pub fn new(name: impl Into<String>, rate: Rate) -> Self {
+        let backend = Arc::new(LocalBackend::new(&rate));
+        RateLimitLayer::with_backend(name, rate, backend)
+    }
+
+    /// Create new rate limit layer whose bucket is stored in the backend, to
+    /// share it with other processes.
+    pub fn with_backend(
+        name: impl Into<String>,
+        rate: Rate,
+        backend: RateLimitBackendInstance,
+    ) -> Self {
+        let effective_rate = EffectiveRate::new(rate.per_second as f64);
+        RateLimitLayer { name: name.into(), rate, backend, effective_rate }
+    }
+
+    /// Returns a handle to monitor the effective rate of the layer.
+    pub fn effective_rate(&self) -> EffectiveRate {
+        self.effective_rate.clone()
     }
 }
 
@@ -34,79 +112,435 @@ impl<S> Layer<S> for RateLimitLayer {
     type Service = RateLimit<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        RateLimit::new(service, self.rate)
+        RateLimit {
+            inner: service,
+            name: Arc::from(self.name.as_str()),
+            rate: self.rate.clone(),
+            backend: Arc::clone(&self.backend),
+            effective_rate: self.effective_rate.clone(),
+        }
     }
 }
 
-/// Enforces a rate limit on the number of requests the underlying
-/// service can handle over a period of time.
+/// Compute units per second currently allowed by a rate limit, lower than the
+/// configured rate while an adaptive rate limit is backing off.
+#[derive(Debug, Clone)]
+pub struct EffectiveRate {
+    /// Bits of the rate, as seen by the last request.
+    rate: Arc<AtomicU64>,
+}
+
+impl EffectiveRate {
+    fn new(rate: f64) -> Self {
+        EffectiveRate { rate: Arc::new(AtomicU64::new(rate.to_bits())) }
+    }
+
+    fn set(&self, rate: f64) {
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+}
+
+/// Enforces a rate limit on the compute units the underlying service can
+/// spend, with a token bucket that allows bursts.
 #[derive(Debug, Clone)]
 pub struct RateLimit<T> {
     inner: T,
+    name: Arc<str>,
     rate: Rate,
-    state: Arc<Mutex<State>>,
+    backend: RateLimitBackendInstance,
+    effective_rate: EffectiveRate,
+}
+
+/// Token bucket of a rate limit. The times are in seconds since the Unix
+/// epoch, so the bucket can be shared between processes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bucket {
+    /// Available compute units. It is negative when the requests waiting for
+    /// their turn reserved more than available.
+    tokens: f64,
+    refilled_at: f64,
+    /// Compute units refilled per second.
+    rate: f64,
+    backoff_at: Option<f64>,
+}
+
+fn now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
 }
 
-#[derive(Debug)]
-struct State {
-    until: Instant,
-    reserved: u64,
-    timer: Pin<Box<Sleep>>,
+impl Bucket {
+    pub(crate) fn new(rate: &Rate) -> Self {
+        Bucket {
+            tokens: rate.burst as f64,
+            refilled_at: now(),
+            rate: rate.per_second as f64,
+            backoff_at: None,
+        }
+    }
+
+    /// Takes the compute units of a request, returning how long to wait until
+    /// they are available. The requests are served in the order they are made.
+    pub(crate) fn reserve(&mut self, rate: &Rate, cost: u64) -> Duration {
+        let now = now();
+        let elapsed = (now - self.refilled_at).max(0.0);
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(rate.burst as f64);
+        self.refilled_at = now;
+
+        if rate.adaptive {
+            let ceiling = rate.per_second as f64;
+            self.rate = (self.rate + elapsed * ceiling * RECOVERY_PER_SECOND).min(ceiling);
+        }
+
+        self.tokens -= cost as f64;
+
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// Lowers the rate after a rate limited request, returning the new rate
+    /// unless it was already lowered for the same burst of requests.
+    fn backoff(&mut self, rate: &Rate) -> Option<f64> {
+        let now = now();
+
+        if self
+            .backoff_at
+            .is_some_and(|backoff_at| now < backoff_at + BACKOFF_INTERVAL.as_secs_f64())
+        {
+            return None;
+        }
+
+        let min_rate = (rate.per_second as f64 * MIN_RATE).max(1.0);
+
+        self.rate = (self.rate * BACKOFF_FACTOR).max(min_rate);
+        self.tokens = self.tokens.min(0.0);
+        self.backoff_at = Some(now);
+
+        Some(self.rate)
+    }
 }
 
-impl<T> RateLimit<T> {
-    /// Create a new rate limiter
-    pub fn new(inner: T, rate: Rate) -> Self {
-        let until = Instant::now() + rate.period;
+/// Returns whether a JSON-RPC error is caused by the rate limit of the RPC.
+fn rate_limit_error(response: &Response) -> bool {
+    response.payload.as_error().is_some_and(|error| {
+        let message = error.message.to_lowercase();
 
-        let state = Arc::new(Mutex::new(State {
-            until,
-            reserved: rate.limit,
-            timer: Box::pin(tokio::time::sleep_until(until)),
-        }));
+        error.code == 429
+            || message.contains("rate limit")
+            || message.contains("too many requests")
+            || message.contains("compute units per second")
+    })
+}
 
-        RateLimit { inner, rate, state }
+/// Returns whether the RPC rejected the request because of its rate limit,
+/// with an HTTP 429 or a JSON-RPC rate limit error.
+fn rate_limited(response: &Result<ResponsePacket, TransportError>) -> bool {
+    match response {
+        Ok(ResponsePacket::Single(single)) => rate_limit_error(single),
+        Ok(ResponsePacket::Batch(batch)) => batch.iter().any(rate_limit_error),
+        Err(RpcError::Transport(TransportErrorKind::HttpError(error))) => error.is_rate_limit_err(),
+        Err(RpcError::Transport(TransportErrorKind::Custom(error))) => {
+            error.to_string().contains("429 Too Many Requests")
+        }
+        Err(_) => false,
     }
 }
 
-impl<S, Request> Service<Request> for RateLimit<S>
+/// Applies an update to the bucket of the backend, on a blocking thread when
+/// the backend blocks, returning the output of the update.
+async fn update_bucket<T, F>(
+    backend: &RateLimitBackendInstance,
+    mut update: F,
+) -> Result<T, TransportError>
+where
+    T: Default + Send + 'static,
+    F: FnMut(&mut Bucket) -> T + Send + 'static,
+{
+    let blocking = backend.is_blocking();
+    let backend = Arc::clone(backend);
+
+    let mut apply = move || {
+        let mut output = T::default();
+        backend.update(&mut |bucket| output = update(bucket)).map(|_| output)
+    };
+
+    let result = match blocking {
+        true => tokio::task::spawn_blocking(apply).await.map_err(TransportError::local_usage)?,
+        false => apply(),
+    };
+
+    result.map_err(TransportError::local_usage)
+}
+
+/// Takes the compute units of a request from the bucket, returning how long
+/// to wait until they are available.
+async fn reserve(
+    backend: &RateLimitBackendInstance,
+    rate: &Rate,
+    cost: u64,
+    effective_rate: &EffectiveRate,
+) -> Result<Duration, TransportError> {
+    let bucket_rate = rate.clone();
+
+    let (wait, current_rate) =
+        update_bucket(backend, move |bucket| (bucket.reserve(&bucket_rate, cost), bucket.rate))
+            .await?;
+
+    effective_rate.set(current_rate);
+
+    Ok(wait)
+}
+
+impl<S> Service<RequestPacket> for RateLimit<S>
 where
-    S: Service<Request>,
+    S: Service<RequestPacket, Response = ResponsePacket, Error = TransportError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = S::Future;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        let now = Instant::now();
-        let mut state = self.state.lock().unwrap();
+        self.inner.poll_ready(ctx)
+    }
 
-        if now >= state.until {
-            state.until = now + self.rate.period;
-            state.reserved = 0;
-            state.timer.as_mut().reset(now + self.rate.period);
-        }
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        let cost = self.rate.cost(&request);
 
-        if state.reserved >= self.rate.limit {
-            ctx.waker().wake_by_ref();
-            let _ = state.timer.as_mut().poll(ctx);
-            return Poll::Pending;
-        }
+        // The rate limited requests are sent again by an adaptive rate limit
+        let retry = self.rate.adaptive.then(|| (self.inner.clone(), request.clone()));
 
-        match self.inner.poll_ready(ctx) {
-            Poll::Ready(value) => {
-                state.reserved += 1;
-                Poll::Ready(value)
-            }
-            Poll::Pending => {
-                ctx.waker().wake_by_ref();
-                let _ = state.timer.as_mut().poll(ctx);
-                Poll::Pending
+        // The request is only sent once the future is polled after the wait
+        let future = self.inner.call(request);
+
+        let name = Arc::clone(&self.name);
+        let rate = self.rate.clone();
+        let backend = Arc::clone(&self.backend);
+        let effective_rate = self.effective_rate.clone();
+
+        Box::pin(async move {
+            let wait = reserve(&backend, &rate, cost, &effective_rate).await?;
+            tokio::time::sleep(wait).await;
+            let mut response = future.await;
+
+            let Some((mut inner, request)) = retry else {
+                return response;
+            };
+
+            for attempt in 1..=MAX_RETRIES {
+                if !rate_limited(&response) {
+                    break;
+                }
+
+                let backoff_rate = rate.clone();
+                let backoff =
+                    update_bucket(&backend, move |bucket| bucket.backoff(&backoff_rate)).await;
+
+                let backoff = backoff.unwrap_or_else(|error| {
+                    eprintln!("[{}] Error backing off the rate limit: {}", name, error);
+                    None
+                });
+
+                if let Some(rate) = backoff {
+                    effective_rate.set(rate);
+
+                    eprintln!(
+                        "[{}] Rate limited by the RPC, backing off to {:.1} compute units per second",
+                        name, rate
+                    );
+                }
+
+                let wait = reserve(&backend, &rate, cost, &effective_rate).await?;
+                tokio::time::sleep(wait).await;
+
+                eprintln!(
+                    "[{}] Retrying a rate limited request ({}/{})",
+                    name, attempt, MAX_RETRIES
+                );
+
+                std::future::poll_fn(|ctx| inner.poll_ready(ctx)).await?;
+                response = inner.call(request.clone()).await;
             }
+
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layers::rate_limit_backend::FileBackend;
+    use alloy::rpc::json_rpc::{ErrorPayload, Id, Request, ResponsePayload};
+    use alloy::transports::TransportFut;
+    use serde_json::value::RawValue;
+    use std::sync::atomic::AtomicU32;
+
+    /// Transport rate limiting the requests until the given number of calls.
+    #[derive(Clone)]
+    struct RateLimitedTransport {
+        calls: Arc<AtomicU32>,
+        rate_limited_calls: u32,
+    }
+
+    impl Service<RequestPacket> for RateLimitedTransport {
+        type Response = ResponsePacket;
+        type Error = TransportError;
+        type Future = TransportFut<'static>;
+
+        fn poll_ready(&mut self, _ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
         }
+
+        fn call(&mut self, _request: RequestPacket) -> Self::Future {
+            let call = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+
+            let payload = match call <= self.rate_limited_calls {
+                true => ResponsePayload::Failure(ErrorPayload {
+                    code: 429,
+                    message: "Too Many Requests".to_string(),
+                    data: None,
+                }),
+                false => ResponsePayload::Success(RawValue::from_string("\"0x1\"".into()).unwrap()),
+            };
+
+            Box::pin(
+                async move { Ok(ResponsePacket::Single(Response { id: Id::Number(0), payload })) },
+            )
+        }
+    }
+
+    async fn send(adaptive: bool, rate_limited_calls: u32) -> (bool, u32, f64) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let transport = RateLimitedTransport { calls: Arc::clone(&calls), rate_limited_calls };
+
+        let rate = Rate::new(1000, 1000, HashMap::new()).adaptive(adaptive);
+        let layer = RateLimitLayer::new("test", rate);
+        let effective_rate = layer.effective_rate();
+
+        let request = RequestPacket::Single(request("eth_blockNumber"));
+        let response = layer.layer(transport).call(request).await;
+
+        (rate_limited(&response), calls.load(Ordering::Relaxed), effective_rate.get())
+    }
+
+    fn request(method: &'static str) -> SerializedRequest {
+        Request::new(method, Id::Number(0), ()).serialize().unwrap()
+    }
+
+    #[test]
+    fn weighs_the_requests_by_method() {
+        let costs = HashMap::from([("eth_getLogs".to_string(), 75)]);
+        let rate = Rate::new(100, 100, costs);
+
+        assert_eq!(rate.cost(&RequestPacket::Single(request("eth_getLogs"))), 75);
+        assert_eq!(rate.cost(&RequestPacket::Single(request("eth_blockNumber"))), 1);
+
+        let batch = RequestPacket::Batch(vec![request("eth_getLogs"), request("eth_chainId")]);
+        assert_eq!(rate.cost(&batch), 76);
+    }
+
+    #[test]
+    fn allows_bursts_then_waits_in_order() {
+        let rate = Rate::new(10, 20, HashMap::new());
+        let mut bucket = Bucket::new(&rate);
+
+        assert_eq!(bucket.reserve(&rate, 20), Duration::ZERO);
+
+        // Each request waits for the requests reserved before it
+        let first = bucket.reserve(&rate, 5);
+        let second = bucket.reserve(&rate, 5);
+
+        assert!((first.as_secs_f64() - 0.5).abs() < 0.05);
+        assert!((second.as_secs_f64() - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn refills_up_to_the_burst() {
+        let rate = Rate::new(10, 20, HashMap::new());
+        let mut bucket = Bucket::new(&rate);
+        bucket.refilled_at -= 60.0;
+
+        assert_eq!(bucket.reserve(&rate, 20), Duration::ZERO);
+        assert!(bucket.reserve(&rate, 1) > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn retries_the_rate_limited_requests() {
+        let (rate_limited, calls, effective_rate) = send(true, 2).await;
+
+        assert!(!rate_limited);
+        assert_eq!(calls, 3);
+        assert!(effective_rate < 1000.0);
+    }
+
+    #[tokio::test]
+    async fn bounds_the_retries() {
+        let (rate_limited, calls, _) = send(true, u32::MAX).await;
+
+        assert!(rate_limited);
+        assert_eq!(calls, MAX_RETRIES + 1);
+    }
+
+    #[tokio::test]
+    async fn only_retries_when_adaptive() {
+        let (rate_limited, calls, effective_rate) = send(false, 1).await;
+
+        assert!(rate_limited);
+        assert_eq!(calls, 1);
+        assert_eq!(effective_rate, 1000.0);
     }
 
-    fn call(&mut self, request: Request) -> Self::Future {
-        self.inner.call(request)
+    #[tokio::test]
+    async fn updates_the_file_backend_off_the_runtime() {
+        let directory = std::env::temp_dir()
+            .join(format!("ghost-crab-rate-limit-layer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+
+        let rate = Rate::new(1000, 1000, HashMap::new()).adaptive(true);
+        let backend = FileBackend::open(&directory, "mainnet", &rate).unwrap();
+        let layer = RateLimitLayer::with_backend("test", rate, Arc::new(backend));
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let transport = RateLimitedTransport { calls: Arc::clone(&calls), rate_limited_calls: 1 };
+
+        let request = RequestPacket::Single(request("eth_blockNumber"));
+        let response = layer.layer(transport).call(request).await;
+
+        assert!(!rate_limited(&response));
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(layer.effective_rate().get() < 1000.0);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn backs_off_once_per_burst() {
+        let rate = Rate::new(100, 100, HashMap::new()).adaptive(true);
+        let mut bucket = Bucket::new(&rate);
+
+        assert_eq!(bucket.backoff(&rate), Some(50.0));
+        assert_eq!(bucket.backoff(&rate), None);
+
+        bucket.backoff_at = bucket.backoff_at.map(|backoff_at| backoff_at - 60.0);
+        assert_eq!(bucket.backoff(&rate), Some(25.0));
+
+        for _ in 0..10 {
+            bucket.backoff_at = None;
+            bucket.backoff(&rate);
+        }
+
+        assert_eq!(bucket.rate, 5.0);
     }
 }
@@ -1,10 +1,19 @@
 pub mod block_handler;
+pub mod dynamic_handler;
 pub mod event_handler;
 pub mod indexer;
+pub mod multicall;
 pub mod prelude;
+pub mod store;
+pub mod testing;
+pub mod transaction_handler;
 
 pub use ghost_crab_common::config;
+pub use indexer::builder::IndexerBuilder;
 pub use indexer::indexer::Indexer;
 
+#[cfg(feature = "graphql")]
+mod graphql;
 mod latest_block_manager;
 mod layers;
+mod pending_ranges;
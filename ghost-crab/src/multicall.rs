@@ -0,0 +1,315 @@
+use crate::indexer::rpc_manager::Provider;
+use alloy::eips::BlockId;
+use alloy::primitives::{address, Address, Bytes};
+use alloy::providers::Provider as AlloyProvider;
+use alloy::rpc::types::eth::{TransactionInput, TransactionRequest};
+use alloy::sol;
+use alloy::sol_types::SolCall;
+use alloy::transports::{TransportError, TransportErrorKind};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, OnceCell};
+
+/// Address of the Multicall3 contract, deployed at the same address on most chains.
+pub const MULTICALL3_ADDRESS: Address = address!("cA11bde05977b3631167028862bE2a173976CA11");
+
+/// Suffix of the calldata of the `aggregate3` calls sent by [`Multicall`],
+/// ignored by the contract. Only these calls are split into their calls by
+/// the cache, the other calls to Multicall3 are cached as they are.
+pub(crate) const AGGREGATE_MARKER: &[u8] = b"ghost-crab";
+
+sol! {
+    interface IMulticall3 {
+        struct Call3 {
+            address target;
+            bool allowFailure;
+            bytes callData;
+        }
+
+        struct Call3Result {
+            bool success;
+            bytes returnData;
+        }
+
+        function aggregate3(Call3[] calldata calls) external payable returns (Call3Result[] memory returnData);
+    }
+}
+
+struct PendingCall {
+    target: Address,
+    call_data: Bytes,
+    sender: oneshot::Sender<Result<Bytes, String>>,
+}
+
+/// Aggregates the `eth_call`s issued concurrently for the same block into a
+/// single Multicall3 `aggregate3` call. The result of each call is cached
+/// individually, so it can be served again without the other calls.
+///
+/// ```ignore
+/// let multicall = ctx.multicall();
+///
+/// let (total_assets, total_supply) = tokio::join!(
+///     multicall.call(STADER, StaderStakePoolsManager::totalAssetsCall {}),
+///     multicall.call(STADER, StaderStakePoolsManager::totalSupplyCall {}),
+/// );
+/// ```
+///
+/// Before Multicall3 is deployed on the network, the calls are sent
+/// individually.
+#[derive(Clone)]
+pub struct Multicall {
+    provider: Provider,
+    block: BlockId,
+    pending: Arc<Mutex<Vec<PendingCall>>>,
+    /// Whether Multicall3 is deployed at the block, checked on the first batch.
+    deployed: Arc<OnceCell<bool>>,
+}
+
+impl Multicall {
+    pub fn new(provider: Provider, block: BlockId) -> Self {
+        Multicall {
+            provider,
+            block,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            deployed: Arc::new(OnceCell::new()),
+        }
+    }
+
+    /// Calls a view of a contract, batched with the other calls issued
+    /// concurrently through this multicall.
+    pub async fn call<C: SolCall>(
+        &self,
+        target: Address,
+        call: C,
+    ) -> Result<C::Return, TransportError> {
+        let (sender, receiver) = oneshot::channel();
+        let call_data = Bytes::from(call.abi_encode());
+
+        self.pending.lock().unwrap().push(PendingCall { target, call_data, sender });
+
+        // Lets the calls issued concurrently join the batch
+        tokio::task::yield_now().await;
+
+        let batch = std::mem::take(&mut *self.pending.lock().unwrap());
+
+        if !batch.is_empty() {
+            self.execute(batch).await;
+        }
+
+        let return_data = receiver
+            .await
+            .map_err(|_| TransportErrorKind::custom_str("The multicall was cancelled"))?
+            .map_err(|error| TransportErrorKind::custom_str(&error))?;
+
+        C::abi_decode_returns(&return_data, true).map_err(TransportError::local_usage)
+    }
+
+    async fn execute(&self, mut batch: Vec<PendingCall>) {
+        // A single call is sent as is, without the overhead of the multicall
+        if batch.len() == 1 {
+            let pending = batch.remove(0);
+            let result = self.eth_call(pending.target, pending.call_data).await;
+            let _ = pending.sender.send(result.map_err(|error| error.to_string()));
+            return;
+        }
+
+        match self.deployed().await {
+            Ok(true) => {}
+            Ok(false) => return self.execute_each(batch),
+            Err(error) => {
+                for pending in batch {
+                    let _ = pending.sender.send(Err(error.to_string()));
+                }
+
+                return;
+            }
+        }
+
+        let calls = batch
+            .iter()
+            .map(|pending| IMulticall3::Call3 {
+                target: pending.target,
+                allowFailure: true,
+                callData: pending.call_data.clone(),
+            })
+            .collect();
+
+        let call_data =
+            [&IMulticall3::aggregate3Call { calls }.abi_encode(), AGGREGATE_MARKER].concat();
+
+        let results = match self.eth_call(MULTICALL3_ADDRESS, call_data.into()).await {
+            Ok(return_data) => IMulticall3::aggregate3Call::abi_decode_returns(&return_data, true)
+                .map(|results| results.returnData)
+                .map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string()),
+        };
+
+        match results {
+            Ok(results) if results.len() == batch.len() => {
+                for (pending, result) in batch.into_iter().zip(results) {
+                    let result = match result.success {
+                        true => Ok(result.returnData),
+                        false => Err(format!("Multicall call reverted: {}", result.returnData)),
+                    };
+
+                    let _ = pending.sender.send(result);
+                }
+            }
+            Ok(results) => {
+                let error = format!(
+                    "Multicall returned {} results for {} calls",
+                    results.len(),
+                    batch.len()
+                );
+
+                for pending in batch {
+                    let _ = pending.sender.send(Err(error.clone()));
+                }
+            }
+            Err(error) => {
+                for pending in batch {
+                    let _ = pending.sender.send(Err(error.clone()));
+                }
+            }
+        }
+    }
+
+    async fn deployed(&self) -> Result<bool, TransportError> {
+        let deployed = self.deployed.get_or_try_init(|| async {
+            let code = self.provider.get_code_at(MULTICALL3_ADDRESS).block_id(self.block).await?;
+            Ok::<_, TransportError>(!code.is_empty())
+        });
+
+        deployed.await.copied()
+    }
+
+    /// Sends each call of the batch concurrently, without Multicall3.
+    fn execute_each(&self, batch: Vec<PendingCall>) {
+        for pending in batch {
+            let multicall = self.clone();
+
+            tokio::spawn(async move {
+                let result = multicall.eth_call(pending.target, pending.call_data).await;
+                let _ = pending.sender.send(result.map_err(|error| error.to_string()));
+            });
+        }
+    }
+
+    async fn eth_call(&self, target: Address, call_data: Bytes) -> Result<Bytes, TransportError> {
+        let transaction =
+            TransactionRequest::default().to(target).input(TransactionInput::new(call_data));
+
+        self.provider.call(&transaction).block(self.block).await
+    }
+}
+
+/// Returns the params of an `eth_call` of a contract, serialized as the
+/// provider does, so the cache keys of the aggregated calls match the keys of
+/// the same calls made directly.
+pub(crate) fn call_params(target: Address, call_data: &Bytes, block: &Value) -> Value {
+    json!([{ "input": call_data, "to": target }, block])
+}
+
+/// Returns the calls and the block of an `eth_call` to the Multicall3
+/// `aggregate3` sent by [`Multicall`].
+pub(crate) fn decode_aggregate3(params: &Value) -> Option<(Vec<IMulticall3::Call3>, Value)> {
+    let transaction = params.get(0)?;
+    let block = params.get(1)?;
+
+    let to: Address = serde_json::from_value(transaction.get("to")?.clone()).ok()?;
+
+    if to != MULTICALL3_ADDRESS {
+        return None;
+    }
+
+    let input = transaction.get("input").or(transaction.get("data"))?;
+    let input: Bytes = serde_json::from_value(input.clone()).ok()?;
+
+    let input = input.strip_suffix(AGGREGATE_MARKER)?;
+    let call = IMulticall3::aggregate3Call::abi_decode(input, true).ok()?;
+
+    Some((call.calls, block.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockTransport;
+    use alloy::primitives::U256;
+
+    sol! {
+        function totalAssets() external view returns (uint256);
+    }
+
+    const VAULT: Address = address!("0000000000000000000000000000000000000001");
+
+    async fn total_assets(multicall: &Multicall) -> Vec<U256> {
+        let (first, second) = tokio::join!(
+            multicall.call(VAULT, totalAssetsCall {}),
+            multicall.call(VAULT, totalAssetsCall {}),
+        );
+
+        vec![first.unwrap()._0, second.unwrap()._0]
+    }
+
+    fn eth_call_targets(transport: &MockTransport) -> Vec<Value> {
+        transport
+            .requests()
+            .into_iter()
+            .filter(|(method, _)| method == "eth_call")
+            .map(|(_, params)| params[0]["to"].clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn aggregates_the_calls() {
+        let transport = MockTransport::new();
+        let return_data = Bytes::from(U256::from(7).to_be_bytes::<32>());
+        let result = IMulticall3::Call3Result { success: true, returnData: return_data };
+        let results = IMulticall3::aggregate3Call::abi_encode_returns(&(vec![result; 2],));
+
+        transport.push_response("eth_getCode", json!("0x6080"));
+        transport.push_response("eth_call", json!(Bytes::from(results)));
+
+        let multicall = Multicall::new(transport.provider(), BlockId::number(100));
+
+        assert_eq!(total_assets(&multicall).await, vec![U256::from(7); 2]);
+        assert_eq!(eth_call_targets(&transport), vec![json!(MULTICALL3_ADDRESS)]);
+
+        let (_, params) = transport.requests().pop().unwrap();
+        let (calls, block) = decode_aggregate3(&params).unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(block, json!("0x64"));
+    }
+
+    #[tokio::test]
+    async fn sends_the_calls_individually_before_the_deployment() {
+        let transport = MockTransport::new();
+        let return_data = Bytes::from(U256::from(7).to_be_bytes::<32>());
+
+        transport.push_response("eth_getCode", json!("0x"));
+        transport.push_response("eth_call", json!(return_data));
+        transport.push_response("eth_call", json!(return_data));
+
+        let multicall = Multicall::new(transport.provider(), BlockId::number(100));
+
+        assert_eq!(total_assets(&multicall).await, vec![U256::from(7); 2]);
+        assert_eq!(eth_call_targets(&transport), vec![json!(VAULT); 2]);
+    }
+
+    #[test]
+    fn only_decodes_the_marked_aggregates() {
+        let calls = vec![IMulticall3::Call3 {
+            target: VAULT,
+            allowFailure: true,
+            callData: totalAssetsCall {}.abi_encode().into(),
+        }];
+
+        let call_data = Bytes::from(IMulticall3::aggregate3Call { calls }.abi_encode());
+        let marked = Bytes::from([&call_data[..], AGGREGATE_MARKER].concat());
+
+        let block = json!("0x64");
+        assert!(decode_aggregate3(&call_params(MULTICALL3_ADDRESS, &call_data, &block)).is_none());
+        assert!(decode_aggregate3(&call_params(MULTICALL3_ADDRESS, &marked, &block)).is_some());
+    }
+}
@@ -0,0 +1,448 @@
+use crate::event_handler::HandlerResult;
+use crate::layers::cache_layer::is_cache_miss;
+use crate::store::{Store, StoreTransaction};
+use alloy::primitives::B256;
+use alloy::transports::TransportError;
+use ghost_crab_common::config::ConcurrencyConfig;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinHandle;
+
+struct PendingRange {
+    tasks: Vec<JoinHandle<HandlerResult>>,
+    store: StoreTransaction,
+    end_block: u64,
+}
+
+/// Ranges whose handlers may still be running. The handlers of a range are
+/// awaited, and its writes committed, before the cursor advances more than
+/// `max_pending_ranges` ranges past it. When a handler fails, its range is not
+/// committed and the error is returned, aborting the handlers still running.
+///
+/// A handler that panics fails its range the same way, so its partial writes
+/// are not committed and its logs are handled again after a restart.
+///
+/// Running offline, a request missing from the cache doesn't stop the source,
+/// so every missing request is reported, but the ranges from the first miss
+/// are not committed as their writes are incomplete.
+pub struct PendingRanges {
+    checkpoint: String,
+    /// Whether a request was missing from the cache.
+    incomplete: bool,
+    max_pending_ranges: usize,
+    ranges: VecDeque<PendingRange>,
+    tasks: Vec<JoinHandle<HandlerResult>>,
+    semaphore: Option<Arc<Semaphore>>,
+    /// Completion of the last task spawned for each key, awaited by the next
+    /// task of the key so the tasks of a key run in order across ranges.
+    keys: HashMap<B256, oneshot::Receiver<()>>,
+}
+
+impl PendingRanges {
+    pub fn new(checkpoint: String, config: &ConcurrencyConfig) -> Self {
+        Self {
+            checkpoint,
+            incomplete: false,
+            max_pending_ranges: config.max_pending_ranges.unwrap_or(1).max(1),
+            ranges: VecDeque::new(),
+            tasks: Vec::new(),
+            semaphore: config.max_concurrency.map(|permits| Arc::new(Semaphore::new(permits))),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Starts the transaction of the next range, which reads the writes of the
+    /// pending ranges, e.g. of the previous handler of a key, until they are
+    /// committed.
+    pub fn transaction(&self, store: &Store) -> StoreTransaction {
+        store.transaction().after(self.ranges.iter().map(|range| &range.store))
+    }
+
+    /// Returns the value, or `None` when a request was missing from the cache,
+    /// which leaves the current and following ranges uncommitted.
+    pub fn tolerate_miss<T>(
+        &mut self,
+        result: Result<T, TransportError>,
+    ) -> Result<Option<T>, TransportError> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(error) if is_cache_miss(&error) => {
+                self.miss();
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn miss(&mut self) {
+        if !self.incomplete {
+            eprintln!(
+                "[{}] Request missing from the cache, the following ranges are not committed",
+                self.checkpoint
+            );
+        }
+
+        self.incomplete = true;
+    }
+
+    /// Spawns a handler of the current range, waiting for another handler to
+    /// finish when `max_concurrency` handlers are already running.
+    pub async fn spawn<F>(&mut self, handler: F) -> Result<(), TransportError>
+    where
+        F: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(
+                Arc::clone(semaphore).acquire_owned().await.map_err(TransportError::local_usage)?,
+            ),
+            None => None,
+        };
+
+        self.tasks.push(tokio::spawn(async move {
+            let result = handler.await;
+            drop(permit);
+            result
+        }));
+
+        Ok(())
+    }
+
+    /// Spawns a handler of the current range that starts once the previous
+    /// handler spawned for the same key is done.
+    pub async fn spawn_keyed<F>(&mut self, key: B256, handler: F) -> Result<(), TransportError>
+    where
+        F: Future<Output = HandlerResult> + Send + 'static,
+    {
+        let (done, receiver) = oneshot::channel();
+        let previous = self.keys.insert(key, receiver);
+
+        self.spawn(async move {
+            if let Some(previous) = previous {
+                // The previous task may have failed, which drops the sender
+                let _ = previous.await;
+            }
+
+            handler.await?;
+            let _ = done.send(());
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Ends the current range, committing the oldest ranges until at most
+    /// `max_pending_ranges` are pending. The ranges whose handlers are done
+    /// are committed right away.
+    pub async fn push(
+        &mut self,
+        store: StoreTransaction,
+        end_block: u64,
+    ) -> Result<(), TransportError> {
+        let tasks = std::mem::take(&mut self.tasks);
+        self.ranges.push_back(PendingRange { tasks, store, end_block });
+
+        while self.ranges.len() > self.max_pending_ranges
+            || self
+                .ranges
+                .front()
+                .is_some_and(|range| range.tasks.iter().all(JoinHandle::is_finished))
+        {
+            self.commit_oldest().await?;
+        }
+
+        self.forget_keys();
+
+        Ok(())
+    }
+
+    /// Commits every pending range, e.g. before waiting for new blocks.
+    pub async fn flush(&mut self) -> Result<(), TransportError> {
+        while !self.ranges.is_empty() {
+            self.commit_oldest().await?;
+        }
+
+        self.forget_keys();
+
+        Ok(())
+    }
+
+    /// Forgets the keys once every handler is done, as there is nothing left
+    /// to wait for.
+    fn forget_keys(&mut self) {
+        if self.ranges.is_empty() && self.tasks.is_empty() {
+            self.keys.clear();
+        }
+    }
+
+    async fn commit_oldest(&mut self) -> Result<(), TransportError> {
+        let Some(range) = self.ranges.front_mut() else {
+            return Ok(());
+        };
+
+        let mut missed = false;
+
+        // The tasks stay in the range until they are done, so they are aborted when one fails
+        while let Some(task) = range.tasks.last_mut() {
+            let result = task.await;
+            range.tasks.pop();
+
+            let result = match result {
+                Ok(result) => result.map_err(TransportError::LocalUsageError),
+                Err(error) => Err(TransportError::local_usage(error)),
+            };
+
+            match result {
+                Err(error) if is_cache_miss(&error) => missed = true,
+                result => result?,
+            }
+        }
+
+        if missed {
+            self.miss();
+        }
+
+        let Some(range) = self.ranges.pop_front() else {
+            return Ok(());
+        };
+
+        if self.incomplete {
+            return Ok(());
+        }
+
+        range
+            .store
+            .commit(&self.checkpoint, range.end_block)
+            .await
+            .map_err(TransportError::local_usage)
+    }
+}
+
+impl Drop for PendingRanges {
+    /// Aborts the handlers still running when the source stops, as their
+    /// ranges won't be committed.
+    fn drop(&mut self) {
+        let pending = self.ranges.iter().flat_map(|range| range.tasks.iter());
+
+        for task in pending.chain(self.tasks.iter()) {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::layers::cache_layer::CacheMiss;
+    use crate::store::sqlite::SqliteStore;
+    use crate::store::StoreBackend;
+    use alloy::transports::TransportErrorKind;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    async fn store() -> Store {
+        let store = SqliteStore::connect("sqlite::memory:").unwrap();
+        store.migrate().await.unwrap();
+
+        let store = Store::new(Arc::new(store));
+        store.transaction().commit("Vault", 0).await.unwrap();
+        store
+    }
+
+    fn pending_ranges(max_pending_ranges: usize) -> PendingRanges {
+        let config = ConcurrencyConfig {
+            max_pending_ranges: Some(max_pending_ranges),
+            ..Default::default()
+        };
+
+        PendingRanges::new("Vault".to_string(), &config)
+    }
+
+    #[tokio::test]
+    async fn commits_once_the_handlers_are_done() {
+        let store = store().await;
+        let mut ranges = pending_ranges(1);
+        let (done, receiver) = oneshot::channel::<()>();
+
+        ranges
+            .spawn(async move {
+                let _ = receiver.await;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        ranges.push(store.transaction(), 10).await.unwrap();
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(0));
+
+        let _ = done.send(());
+        ranges.flush().await.unwrap();
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn bounds_the_pending_ranges() {
+        let store = store().await;
+        let mut ranges = pending_ranges(1);
+        let (done, receiver) = oneshot::channel::<()>();
+
+        ranges
+            .spawn(async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        ranges.push(store.transaction(), 10).await.unwrap();
+
+        ranges
+            .spawn(async move {
+                let _ = receiver.await;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        // The first range is awaited before the cursor moves past the second one
+        ranges.push(store.transaction(), 20).await.unwrap();
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(10));
+
+        let _ = done.send(());
+        ranges.flush().await.unwrap();
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(20));
+    }
+
+    #[tokio::test]
+    async fn does_not_commit_a_range_whose_handler_panicked() {
+        let store = store().await;
+        let mut ranges = pending_ranges(1);
+        let transaction = store.transaction();
+        let partial = transaction.clone();
+
+        ranges
+            .spawn(async move {
+                partial.save("Vault", "vault", &serde_json::json!({ "assets": 1 }))?;
+                panic!("Unexpected log")
+            })
+            .await
+            .unwrap();
+
+        ranges.spawn(async { Ok(()) }).await.unwrap();
+        ranges.push(transaction, 10).await.unwrap();
+
+        assert!(ranges.flush().await.is_err());
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(0));
+
+        let vault = store.transaction().load::<serde_json::Value>("Vault", "vault").await.unwrap();
+        assert!(vault.is_none());
+    }
+
+    #[tokio::test]
+    async fn does_not_commit_a_failed_range() {
+        let store = store().await;
+        let mut ranges = pending_ranges(1);
+
+        ranges.spawn(async { Err("Unexpected state".into()) }).await.unwrap();
+        ranges.push(store.transaction(), 10).await.unwrap();
+
+        assert!(ranges.flush().await.is_err());
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn stops_committing_after_a_cache_miss() {
+        let store = store().await;
+        let mut ranges = pending_ranges(1);
+
+        ranges.push(store.transaction(), 10).await.unwrap();
+
+        let miss = CacheMiss { method: "eth_call".to_string(), params: serde_json::Value::Null };
+        let result: Result<(), _> = Err(TransportErrorKind::custom(miss));
+        assert_eq!(ranges.tolerate_miss(result).unwrap(), None);
+
+        ranges.push(store.transaction(), 20).await.unwrap();
+        ranges.flush().await.unwrap();
+
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(10));
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Counter {
+        count: u64,
+    }
+
+    #[tokio::test]
+    async fn reads_the_writes_of_the_pending_ranges() {
+        let store = store().await;
+        let mut ranges = pending_ranges(2);
+        let (done, receiver) = oneshot::channel::<()>();
+        let (handled, mut handled_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        // Keeps the first range pending
+        ranges
+            .spawn(async move {
+                let _ = receiver.await;
+                Ok(())
+            })
+            .await
+            .unwrap();
+
+        for end_block in [10, 20] {
+            let transaction = ranges.transaction(&store);
+            let store = transaction.clone();
+            let handled = handled.clone();
+
+            ranges
+                .spawn_keyed(B256::ZERO, async move {
+                    let counter = store.load::<Counter>("Counter", "Vault").await?;
+                    let count = counter.map(|counter| counter.count).unwrap_or_default();
+
+                    store.save("Counter", "Vault", &Counter { count: count + 1 })?;
+                    let _ = handled.send(());
+                    Ok(())
+                })
+                .await
+                .unwrap();
+
+            ranges.push(transaction, end_block).await.unwrap();
+        }
+
+        // Both writes are made before the first range is committed
+        handled_receiver.recv().await.unwrap();
+        handled_receiver.recv().await.unwrap();
+        assert_eq!(store.checkpoint("Vault").await.unwrap(), Some(0));
+
+        let _ = done.send(());
+        ranges.flush().await.unwrap();
+
+        let counter = store.transaction().load::<Counter>("Counter", "Vault").await.unwrap();
+        assert_eq!(counter.map(|counter| counter.count), Some(2));
+    }
+
+    #[tokio::test]
+    async fn runs_the_handlers_of_a_key_in_order() {
+        let mut ranges = pending_ranges(2);
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        for (index, delay) in [(0, 20), (1, 0)] {
+            let handled = Arc::clone(&handled);
+
+            ranges
+                .spawn_keyed(B256::ZERO, async move {
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    handled.lock().unwrap().push(index);
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        }
+
+        ranges.push(Store::default().transaction(), 10).await.unwrap();
+        ranges.flush().await.unwrap();
+
+        assert_eq!(*handled.lock().unwrap(), vec![0, 1]);
+    }
+}
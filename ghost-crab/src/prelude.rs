@@ -1,21 +1,33 @@
-pub use crate::event_handler::{EventContext, EventHandler};
+pub use crate::event_handler::{
+    BatchEventContext, EventContext, EventHandler, EventHandlerInstance, HandlerError,
+    HandlerResult, IntoHandlerResult,
+};
 pub use alloy;
 pub use alloy::{
     sol,
-    sol_types::{SolEvent, SolEventInterface},
+    sol_types::{SolCall, SolEvent, SolEventInterface},
 };
 pub use async_trait::async_trait;
 pub use config::ExecutionMode;
+pub use ghost_crab_macros::batch_event_handler;
 pub use ghost_crab_macros::block_handler;
+pub use ghost_crab_macros::entities;
 pub use ghost_crab_macros::event_handler;
 pub use ghost_crab_macros::template;
+pub use ghost_crab_macros::transaction_handler;
+pub use serde;
 pub use std::sync::Arc;
 pub use tokio;
 
 pub use crate::block_handler::{BlockContext, BlockHandler};
 pub use crate::config;
+pub use crate::dynamic_handler;
+pub use crate::dynamic_handler::{DynamicEvent, DynamicEventContext, DynamicEventHandler};
 pub use crate::indexer;
 pub use crate::indexer::templates::Template;
+pub use crate::multicall::Multicall;
+pub use crate::store::{StoreError, StoreTransaction};
+pub use crate::transaction_handler::{TransactionContext, TransactionHandler};
 pub use alloy::primitives::address;
 pub use alloy::primitives::Address;
 pub use alloy::providers::Provider;
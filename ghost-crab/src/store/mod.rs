@@ -0,0 +1,351 @@
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+use alloy::primitives::{Address, TxHash};
+use async_trait::async_trait;
+use core::fmt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotConfigured,
+    UnsupportedDatabase(String),
+    Serialization(serde_json::Error),
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::NotConfigured => {
+                write!(f, "No database configured for the indexer")
+            }
+            StoreError::UnsupportedDatabase(database) => {
+                write!(f, "Unsupported database: {}", database)
+            }
+            StoreError::Serialization(error) => {
+                write!(f, "Error while serializing entity: {}", error)
+            }
+            StoreError::Backend(error) => {
+                write!(f, "Database error: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+/// Where a write comes from, stored alongside the entity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub block_number: u64,
+    pub log_index: Option<u64>,
+    pub tx_hash: Option<TxHash>,
+}
+
+/// A single write made by a handler, applied when the block range is committed.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Save {
+        entity: String,
+        id: String,
+        data: Value,
+        metadata: Metadata,
+    },
+    Delete {
+        entity: String,
+        id: String,
+    },
+    /// A template started by a handler, saved to start it again after a restart.
+    StartTemplate(StartedTemplate),
+}
+
+/// A source started from a template, e.g. for a contract created by a factory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartedTemplate {
+    pub name: String,
+    pub address: Address,
+    pub start_block: u64,
+}
+
+/// An entity as persisted in the store.
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub id: String,
+    pub data: Value,
+    pub metadata: Metadata,
+}
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Id(String),
+    BlockNumber(u64),
+    /// Matches a field of the entity data.
+    Field(String, Value),
+    /// Matches a field of the entity data ignoring the case, for hex values
+    /// like addresses.
+    FieldIgnoreCase(String, Value),
+}
+
+#[derive(Debug, Clone)]
+pub enum OrderBy {
+    Id,
+    BlockNumber,
+    Field(String),
+    /// Orders by a `uint256` field, stored as hex without leading zeros, so
+    /// by its length and then by its text.
+    Uint256(String),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum OrderDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub entity: String,
+    pub filters: Vec<Filter>,
+    pub order_by: OrderBy,
+    pub order_direction: OrderDirection,
+    pub first: u64,
+    pub skip: u64,
+}
+
+/// Database where the entities and the checkpoints of each source are persisted.
+#[async_trait]
+pub trait StoreBackend {
+    /// Creates the tables used by the store if they don't exist.
+    async fn migrate(&self) -> Result<(), StoreError>;
+
+    async fn load(&self, entity: &str, id: &str) -> Result<Option<Value>, StoreError>;
+
+    async fn query(&self, query: &Query) -> Result<Vec<Row>, StoreError>;
+
+    /// Returns the last block committed for the source.
+    async fn checkpoint(&self, source: &str) -> Result<Option<u64>, StoreError>;
+
+    /// Returns the last block committed for every source.
+    async fn checkpoints(&self) -> Result<Vec<(String, u64)>, StoreError>;
+
+    /// Returns the templates started by the committed ranges.
+    async fn templates(&self) -> Result<Vec<StartedTemplate>, StoreError>;
+
+    /// Applies the changes and updates the checkpoint of the source in a single transaction.
+    async fn commit(
+        &self,
+        source: &str,
+        block_number: u64,
+        changes: Vec<Change>,
+    ) -> Result<(), StoreError>;
+}
+
+pub type StoreBackendInstance = Arc<dyn StoreBackend + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Store {
+    backend: Option<StoreBackendInstance>,
+}
+
+impl Store {
+    pub fn new(backend: StoreBackendInstance) -> Store {
+        Store { backend: Some(backend) }
+    }
+
+    /// Connects to the database url of the config, or returns an empty store
+    /// when no database is configured.
+    pub async fn connect(database: Option<&str>) -> Result<Store, StoreError> {
+        let Some(database) = database else {
+            return Ok(Store::default());
+        };
+
+        let backend = open_backend(database)?;
+        backend.migrate().await?;
+
+        Ok(Store::new(backend))
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    pub async fn checkpoint(&self, source: &str) -> Result<Option<u64>, StoreError> {
+        match &self.backend {
+            Some(backend) => backend.checkpoint(source).await,
+            None => Ok(None),
+        }
+    }
+
+    pub async fn query(&self, query: &Query) -> Result<Vec<Row>, StoreError> {
+        let backend = self.backend.as_ref().ok_or(StoreError::NotConfigured)?;
+        backend.query(query).await
+    }
+
+    pub async fn checkpoints(&self) -> Result<Vec<(String, u64)>, StoreError> {
+        match &self.backend {
+            Some(backend) => backend.checkpoints().await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub async fn templates(&self) -> Result<Vec<StartedTemplate>, StoreError> {
+        match &self.backend {
+            Some(backend) => backend.templates().await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn transaction(&self) -> StoreTransaction {
+        StoreTransaction {
+            backend: self.backend.clone(),
+            changes: Arc::new(Mutex::new(Vec::new())),
+            earlier: Vec::new(),
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+fn open_backend(database: &str) -> Result<StoreBackendInstance, StoreError> {
+    match database.split(':').next() {
+        #[cfg(feature = "postgres")]
+        Some("postgres" | "postgresql") => {
+            Ok(Arc::new(postgres::PostgresStore::connect(database)?))
+        }
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") => Ok(Arc::new(sqlite::SqliteStore::connect(database)?)),
+        _ => Err(StoreError::UnsupportedDatabase(database.to_string())),
+    }
+}
+
+/// Buffers the writes made by the handlers of a block range, so they are
+/// committed together with the checkpoint of the source.
+#[derive(Clone)]
+pub struct StoreTransaction {
+    backend: Option<StoreBackendInstance>,
+    changes: Arc<Mutex<Vec<Change>>>,
+    /// Writes of the earlier ranges, oldest first, read until they are committed.
+    earlier: Vec<Arc<Mutex<Vec<Change>>>>,
+    metadata: Metadata,
+}
+
+/// Returns the last write of the entity, `Some(None)` when it was deleted.
+fn find_change(changes: &[Change], entity: &str, id: &str) -> Option<Option<Value>> {
+    changes.iter().rev().find_map(|change| match change {
+        Change::Save { entity: e, id: i, data, .. } if e == entity && i == id => {
+            Some(Some(data.clone()))
+        }
+        Change::Delete { entity: e, id: i } if e == entity && i == id => Some(None),
+        _ => None,
+    })
+}
+
+impl StoreTransaction {
+    /// Returns a handle to the same transaction, whose writes are tagged with the metadata.
+    pub(crate) fn with_metadata(&self, metadata: Metadata) -> StoreTransaction {
+        StoreTransaction {
+            backend: self.backend.clone(),
+            changes: self.changes.clone(),
+            earlier: self.earlier.clone(),
+            metadata,
+        }
+    }
+
+    /// Returns the transaction reading the writes of the earlier transactions,
+    /// oldest first, that are not committed yet.
+    pub(crate) fn after<'a>(
+        mut self,
+        earlier: impl IntoIterator<Item = &'a StoreTransaction>,
+    ) -> StoreTransaction {
+        self.earlier = earlier.into_iter().map(|transaction| transaction.changes.clone()).collect();
+        self
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn save<T: Serialize>(&self, entity: &str, id: &str, value: &T) -> Result<(), StoreError> {
+        if self.backend.is_none() {
+            return Err(StoreError::NotConfigured);
+        }
+
+        let data = serde_json::to_value(value).map_err(StoreError::Serialization)?;
+
+        self.changes.lock().unwrap().push(Change::Save {
+            entity: entity.to_string(),
+            id: id.to_string(),
+            data,
+            metadata: self.metadata,
+        });
+
+        Ok(())
+    }
+
+    pub fn delete(&self, entity: &str, id: &str) -> Result<(), StoreError> {
+        if self.backend.is_none() {
+            return Err(StoreError::NotConfigured);
+        }
+
+        self.changes
+            .lock()
+            .unwrap()
+            .push(Change::Delete { entity: entity.to_string(), id: id.to_string() });
+
+        Ok(())
+    }
+
+    /// Saves a template started by a handler, committed with the range.
+    pub(crate) fn start_template(&self, name: &str, address: Address, start_block: u64) {
+        if self.backend.is_none() {
+            return;
+        }
+
+        self.changes.lock().unwrap().push(Change::StartTemplate(StartedTemplate {
+            name: name.to_string(),
+            address,
+            start_block,
+        }));
+    }
+
+    /// Loads an entity, taking into account the writes not committed yet, of
+    /// this transaction and then of the earlier ranges.
+    pub async fn load<T: DeserializeOwned>(
+        &self,
+        entity: &str,
+        id: &str,
+    ) -> Result<Option<T>, StoreError> {
+        let backend = self.backend.as_ref().ok_or(StoreError::NotConfigured)?;
+
+        let pending = std::iter::once(&self.changes)
+            .chain(self.earlier.iter().rev())
+            .find_map(|changes| find_change(&changes.lock().unwrap(), entity, id));
+
+        let data = match pending {
+            Some(data) => data,
+            None => backend.load(entity, id).await?,
+        };
+
+        data.map(|data| serde_json::from_value(data).map_err(StoreError::Serialization)).transpose()
+    }
+
+    pub(crate) async fn commit(&self, source: &str, block_number: u64) -> Result<(), StoreError> {
+        let Some(backend) = &self.backend else {
+            return Ok(());
+        };
+
+        let changes = self.changes.lock().unwrap().clone();
+        backend.commit(source, block_number, changes).await?;
+
+        // Cleared once committed, so the later ranges read them from the backend
+        self.changes.lock().unwrap().clear();
+
+        Ok(())
+    }
+}
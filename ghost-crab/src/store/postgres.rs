@@ -0,0 +1,319 @@
+use super::{
+    Change, Filter, Metadata, OrderBy, OrderDirection, Query, Row, StartedTemplate, StoreBackend,
+    StoreError,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::{Postgres, QueryBuilder};
+
+fn backend_error(error: sqlx::Error) -> StoreError {
+    StoreError::Backend(Box::new(error))
+}
+
+/// Builds the SQL of the query, whose values are bound as parameters.
+fn build_query(query: &Query) -> QueryBuilder<'static, Postgres> {
+    let mut builder = QueryBuilder::<Postgres>::new(
+        "SELECT id, data, block_number, log_index, tx_hash FROM ghost_crab_entities WHERE entity = ",
+    );
+    builder.push_bind(query.entity.clone());
+
+    for filter in &query.filters {
+        match filter {
+            Filter::Id(id) => {
+                builder.push(" AND id = ");
+                builder.push_bind(id.clone());
+            }
+            Filter::BlockNumber(block_number) => {
+                builder.push(" AND block_number = ");
+                builder.push_bind(*block_number as i64);
+            }
+            Filter::Field(field, value) => {
+                builder.push(" AND data -> ");
+                builder.push_bind(field.clone());
+                builder.push(" = ");
+                builder.push_bind(value.to_string());
+                builder.push("::jsonb");
+            }
+            Filter::FieldIgnoreCase(field, value) => {
+                builder.push(" AND LOWER((data -> ");
+                builder.push_bind(field.clone());
+                builder.push(")::text) = LOWER(");
+                builder.push_bind(value.to_string());
+                builder.push("::jsonb::text)");
+            }
+        }
+    }
+
+    let direction = match query.order_direction {
+        OrderDirection::Asc => " ASC",
+        OrderDirection::Desc => " DESC",
+    };
+
+    builder.push(" ORDER BY ");
+
+    match &query.order_by {
+        OrderBy::Id => {
+            builder.push("id");
+        }
+        OrderBy::BlockNumber => {
+            builder.push("block_number");
+        }
+        OrderBy::Field(field) => {
+            builder.push("data -> ");
+            builder.push_bind(field.clone());
+        }
+        OrderBy::Uint256(field) => {
+            builder.push("LENGTH(data ->> ");
+            builder.push_bind(field.clone());
+            builder.push(")");
+            builder.push(direction);
+            builder.push(", data ->> ");
+            builder.push_bind(field.clone());
+        }
+    }
+
+    builder.push(direction);
+
+    builder.push(", id LIMIT ");
+    builder.push_bind(query.first as i64);
+    builder.push(" OFFSET ");
+    builder.push_bind(query.skip as i64);
+
+    builder
+}
+
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub fn connect(database_url: &str) -> Result<PostgresStore, StoreError> {
+        let pool = PgPoolOptions::new().connect_lazy(database_url).map_err(backend_error)?;
+        Ok(PostgresStore { pool })
+    }
+}
+
+#[async_trait]
+impl StoreBackend for PostgresStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_entities (
+                entity TEXT NOT NULL,
+                id TEXT NOT NULL,
+                data JSONB NOT NULL,
+                block_number BIGINT NOT NULL,
+                log_index BIGINT,
+                tx_hash TEXT,
+                PRIMARY KEY (entity, id)
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        // The tables created before the metadata columns don't have them
+        sqlx::query(
+            "ALTER TABLE ghost_crab_entities
+            ADD COLUMN IF NOT EXISTS block_number BIGINT NOT NULL DEFAULT 0,
+            ADD COLUMN IF NOT EXISTS log_index BIGINT,
+            ADD COLUMN IF NOT EXISTS tx_hash TEXT",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_checkpoints (
+                source TEXT PRIMARY KEY,
+                block_number BIGINT NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_templates (
+                name TEXT NOT NULL,
+                address TEXT NOT NULL,
+                start_block BIGINT NOT NULL,
+                PRIMARY KEY (name, address)
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        Ok(())
+    }
+
+    async fn load(&self, entity: &str, id: &str) -> Result<Option<Value>, StoreError> {
+        let row: Option<(Json<Value>,)> =
+            sqlx::query_as("SELECT data FROM ghost_crab_entities WHERE entity = $1 AND id = $2")
+                .bind(entity)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(backend_error)?;
+
+        Ok(row.map(|(Json(data),)| data))
+    }
+
+    async fn query(&self, query: &Query) -> Result<Vec<Row>, StoreError> {
+        let mut builder = build_query(query);
+
+        let rows: Vec<(String, Json<Value>, i64, Option<i64>, Option<String>)> =
+            builder.build_query_as().fetch_all(&self.pool).await.map_err(backend_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(id, Json(data), block_number, log_index, tx_hash)| Row {
+                id,
+                data,
+                metadata: Metadata {
+                    block_number: block_number as u64,
+                    log_index: log_index.map(|log_index| log_index as u64),
+                    tx_hash: tx_hash.and_then(|tx_hash| tx_hash.parse().ok()),
+                },
+            })
+            .collect())
+    }
+
+    async fn checkpoint(&self, source: &str) -> Result<Option<u64>, StoreError> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT block_number FROM ghost_crab_checkpoints WHERE source = $1")
+                .bind(source)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(backend_error)?;
+
+        Ok(row.map(|(block_number,)| block_number as u64))
+    }
+
+    async fn checkpoints(&self) -> Result<Vec<(String, u64)>, StoreError> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT source, block_number FROM ghost_crab_checkpoints ORDER BY source",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        Ok(rows.into_iter().map(|(source, block_number)| (source, block_number as u64)).collect())
+    }
+
+    async fn templates(&self) -> Result<Vec<StartedTemplate>, StoreError> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT name, address, start_block FROM ghost_crab_templates ORDER BY start_block",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        rows.into_iter()
+            .map(|(name, address, start_block)| {
+                let address =
+                    address.parse().map_err(|error| StoreError::Backend(Box::new(error)))?;
+                Ok(StartedTemplate { name, address, start_block: start_block as u64 })
+            })
+            .collect()
+    }
+
+    async fn commit(
+        &self,
+        source: &str,
+        block_number: u64,
+        changes: Vec<Change>,
+    ) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await.map_err(backend_error)?;
+
+        for change in changes {
+            match change {
+                Change::Save { entity, id, data, metadata } => {
+                    sqlx::query(
+                        "INSERT INTO ghost_crab_entities
+                        (entity, id, data, block_number, log_index, tx_hash)
+                        VALUES ($1, $2, $3, $4, $5, $6)
+                        ON CONFLICT (entity, id) DO UPDATE SET
+                        data = EXCLUDED.data,
+                        block_number = EXCLUDED.block_number,
+                        log_index = EXCLUDED.log_index,
+                        tx_hash = EXCLUDED.tx_hash",
+                    )
+                    .bind(entity)
+                    .bind(id)
+                    .bind(Json(data))
+                    .bind(metadata.block_number as i64)
+                    .bind(metadata.log_index.map(|log_index| log_index as i64))
+                    .bind(metadata.tx_hash.map(|tx_hash| tx_hash.to_string()))
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(backend_error)?;
+                }
+                Change::Delete { entity, id } => {
+                    sqlx::query("DELETE FROM ghost_crab_entities WHERE entity = $1 AND id = $2")
+                        .bind(entity)
+                        .bind(id)
+                        .execute(&mut *transaction)
+                        .await
+                        .map_err(backend_error)?;
+                }
+                Change::StartTemplate(template) => {
+                    // A template started again keeps the block it was first started at
+                    sqlx::query(
+                        "INSERT INTO ghost_crab_templates (name, address, start_block)
+                        VALUES ($1, $2, $3) ON CONFLICT (name, address) DO NOTHING",
+                    )
+                    .bind(template.name)
+                    .bind(template.address.to_string())
+                    .bind(template.start_block as i64)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(backend_error)?;
+                }
+            }
+        }
+
+        sqlx::query(
+            "INSERT INTO ghost_crab_checkpoints (source, block_number) VALUES ($1, $2)
+            ON CONFLICT (source) DO UPDATE SET block_number = EXCLUDED.block_number",
+        )
+        .bind(source)
+        .bind(block_number as i64)
+        .execute(&mut *transaction)
+        .await
+        .map_err(backend_error)?;
+
+        transaction.commit().await.map_err(backend_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn builds_typed_filters_and_order() {
+        let query = Query {
+            entity: "Deposit".to_string(),
+            filters: vec![
+                Filter::Field("count".to_string(), json!(1)),
+                Filter::FieldIgnoreCase("user".to_string(), json!("0xAbC")),
+            ],
+            order_by: OrderBy::Uint256("assets".to_string()),
+            order_direction: OrderDirection::Desc,
+            first: 10,
+            skip: 0,
+        };
+
+        assert_eq!(
+            build_query(&query).sql(),
+            "SELECT id, data, block_number, log_index, tx_hash FROM ghost_crab_entities \
+            WHERE entity = $1 AND data -> $2 = $3::jsonb \
+            AND LOWER((data -> $4)::text) = LOWER($5::jsonb::text) \
+            ORDER BY LENGTH(data ->> $6) DESC, data ->> $7 DESC, id LIMIT $8 OFFSET $9"
+        );
+    }
+}
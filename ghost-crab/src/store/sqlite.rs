@@ -0,0 +1,412 @@
+use super::{
+    Change, Filter, Metadata, OrderBy, OrderDirection, Query, Row, StartedTemplate, StoreBackend,
+    StoreError,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::types::Json;
+use sqlx::{QueryBuilder, Sqlite};
+use std::str::FromStr;
+
+/// Columns added to `ghost_crab_entities` after it was first created.
+const METADATA_COLUMNS: [(&str, &str); 3] =
+    [("block_number", "INTEGER NOT NULL DEFAULT 0"), ("log_index", "INTEGER"), ("tx_hash", "TEXT")];
+
+fn backend_error(error: sqlx::Error) -> StoreError {
+    StoreError::Backend(Box::new(error))
+}
+
+/// Builds the SQL of the query, whose values are bound as parameters.
+fn build_query(query: &Query) -> QueryBuilder<'static, Sqlite> {
+    let mut builder = QueryBuilder::<Sqlite>::new(
+        "SELECT id, data, block_number, log_index, tx_hash FROM ghost_crab_entities WHERE entity = ",
+    );
+    builder.push_bind(query.entity.clone());
+
+    for filter in &query.filters {
+        match filter {
+            Filter::Id(id) => {
+                builder.push(" AND id = ");
+                builder.push_bind(id.clone());
+            }
+            Filter::BlockNumber(block_number) => {
+                builder.push(" AND block_number = ");
+                builder.push_bind(*block_number as i64);
+            }
+            Filter::Field(field, value) => {
+                builder.push(" AND data -> ");
+                builder.push_bind(format!("$.{}", field));
+                builder.push(" = json(");
+                builder.push_bind(value.to_string());
+                builder.push(")");
+            }
+            Filter::FieldIgnoreCase(field, value) => {
+                builder.push(" AND LOWER(data -> ");
+                builder.push_bind(format!("$.{}", field));
+                builder.push(") = LOWER(json(");
+                builder.push_bind(value.to_string());
+                builder.push("))");
+            }
+        }
+    }
+
+    let direction = match query.order_direction {
+        OrderDirection::Asc => " ASC",
+        OrderDirection::Desc => " DESC",
+    };
+
+    builder.push(" ORDER BY ");
+
+    match &query.order_by {
+        OrderBy::Id => {
+            builder.push("id");
+        }
+        OrderBy::BlockNumber => {
+            builder.push("block_number");
+        }
+        OrderBy::Field(field) => {
+            builder.push("json_extract(data, ");
+            builder.push_bind(format!("$.{}", field));
+            builder.push(")");
+        }
+        OrderBy::Uint256(field) => {
+            builder.push("LENGTH(json_extract(data, ");
+            builder.push_bind(format!("$.{}", field));
+            builder.push("))");
+            builder.push(direction);
+            builder.push(", json_extract(data, ");
+            builder.push_bind(format!("$.{}", field));
+            builder.push(")");
+        }
+    }
+
+    builder.push(direction);
+
+    builder.push(", id LIMIT ");
+    builder.push_bind(query.first as i64);
+    builder.push(" OFFSET ");
+    builder.push_bind(query.skip as i64);
+
+    builder
+}
+
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub fn connect(database_url: &str) -> Result<SqliteStore, StoreError> {
+        let options = SqliteConnectOptions::from_str(database_url)
+            .map_err(backend_error)?
+            .create_if_missing(true);
+
+        // SQLite only allows a single writer, so a single connection avoids busy errors
+        let pool = SqlitePoolOptions::new().max_connections(1).connect_lazy_with(options);
+
+        Ok(SqliteStore { pool })
+    }
+}
+
+#[async_trait]
+impl StoreBackend for SqliteStore {
+    async fn migrate(&self) -> Result<(), StoreError> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_entities (
+                entity TEXT NOT NULL,
+                id TEXT NOT NULL,
+                data TEXT NOT NULL,
+                block_number INTEGER NOT NULL,
+                log_index INTEGER,
+                tx_hash TEXT,
+                PRIMARY KEY (entity, id)
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        // The tables created before the metadata columns don't have them, and SQLite
+        // can't add a column only if it doesn't exist
+        let columns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('ghost_crab_entities')")
+                .fetch_all(&self.pool)
+                .await
+                .map_err(backend_error)?;
+
+        for (column, definition) in METADATA_COLUMNS {
+            if columns.iter().any(|(name,)| name == column) {
+                continue;
+            }
+
+            let statement =
+                format!("ALTER TABLE ghost_crab_entities ADD COLUMN {} {}", column, definition);
+
+            sqlx::query(&statement).execute(&self.pool).await.map_err(backend_error)?;
+        }
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_checkpoints (
+                source TEXT PRIMARY KEY,
+                block_number INTEGER NOT NULL
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS ghost_crab_templates (
+                name TEXT NOT NULL,
+                address TEXT NOT NULL,
+                start_block INTEGER NOT NULL,
+                PRIMARY KEY (name, address)
+            )",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        Ok(())
+    }
+
+    async fn load(&self, entity: &str, id: &str) -> Result<Option<Value>, StoreError> {
+        let row: Option<(Json<Value>,)> =
+            sqlx::query_as("SELECT data FROM ghost_crab_entities WHERE entity = ?1 AND id = ?2")
+                .bind(entity)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(backend_error)?;
+
+        Ok(row.map(|(Json(data),)| data))
+    }
+
+    async fn query(&self, query: &Query) -> Result<Vec<Row>, StoreError> {
+        let mut builder = build_query(query);
+
+        let rows: Vec<(String, Json<Value>, i64, Option<i64>, Option<String>)> =
+            builder.build_query_as().fetch_all(&self.pool).await.map_err(backend_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(id, Json(data), block_number, log_index, tx_hash)| Row {
+                id,
+                data,
+                metadata: Metadata {
+                    block_number: block_number as u64,
+                    log_index: log_index.map(|log_index| log_index as u64),
+                    tx_hash: tx_hash.and_then(|tx_hash| tx_hash.parse().ok()),
+                },
+            })
+            .collect())
+    }
+
+    async fn checkpoint(&self, source: &str) -> Result<Option<u64>, StoreError> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT block_number FROM ghost_crab_checkpoints WHERE source = ?1")
+                .bind(source)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(backend_error)?;
+
+        Ok(row.map(|(block_number,)| block_number as u64))
+    }
+
+    async fn checkpoints(&self) -> Result<Vec<(String, u64)>, StoreError> {
+        let rows: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT source, block_number FROM ghost_crab_checkpoints ORDER BY source",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        Ok(rows.into_iter().map(|(source, block_number)| (source, block_number as u64)).collect())
+    }
+
+    async fn templates(&self) -> Result<Vec<StartedTemplate>, StoreError> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT name, address, start_block FROM ghost_crab_templates ORDER BY start_block",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(backend_error)?;
+
+        rows.into_iter()
+            .map(|(name, address, start_block)| {
+                let address =
+                    address.parse().map_err(|error| StoreError::Backend(Box::new(error)))?;
+                Ok(StartedTemplate { name, address, start_block: start_block as u64 })
+            })
+            .collect()
+    }
+
+    async fn commit(
+        &self,
+        source: &str,
+        block_number: u64,
+        changes: Vec<Change>,
+    ) -> Result<(), StoreError> {
+        let mut transaction = self.pool.begin().await.map_err(backend_error)?;
+
+        for change in changes {
+            match change {
+                Change::Save { entity, id, data, metadata } => {
+                    sqlx::query(
+                        "INSERT INTO ghost_crab_entities
+                        (entity, id, data, block_number, log_index, tx_hash)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                        ON CONFLICT (entity, id) DO UPDATE SET
+                        data = EXCLUDED.data,
+                        block_number = EXCLUDED.block_number,
+                        log_index = EXCLUDED.log_index,
+                        tx_hash = EXCLUDED.tx_hash",
+                    )
+                    .bind(entity)
+                    .bind(id)
+                    .bind(Json(data))
+                    .bind(metadata.block_number as i64)
+                    .bind(metadata.log_index.map(|log_index| log_index as i64))
+                    .bind(metadata.tx_hash.map(|tx_hash| tx_hash.to_string()))
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(backend_error)?;
+                }
+                Change::Delete { entity, id } => {
+                    sqlx::query("DELETE FROM ghost_crab_entities WHERE entity = ?1 AND id = ?2")
+                        .bind(entity)
+                        .bind(id)
+                        .execute(&mut *transaction)
+                        .await
+                        .map_err(backend_error)?;
+                }
+                Change::StartTemplate(template) => {
+                    // A template started again keeps the block it was first started at
+                    sqlx::query(
+                        "INSERT INTO ghost_crab_templates (name, address, start_block)
+                        VALUES (?1, ?2, ?3) ON CONFLICT (name, address) DO NOTHING",
+                    )
+                    .bind(template.name)
+                    .bind(template.address.to_string())
+                    .bind(template.start_block as i64)
+                    .execute(&mut *transaction)
+                    .await
+                    .map_err(backend_error)?;
+                }
+            }
+        }
+
+        sqlx::query(
+            "INSERT INTO ghost_crab_checkpoints (source, block_number) VALUES (?1, ?2)
+            ON CONFLICT (source) DO UPDATE SET block_number = EXCLUDED.block_number",
+        )
+        .bind(source)
+        .bind(block_number as i64)
+        .execute(&mut *transaction)
+        .await
+        .map_err(backend_error)?;
+
+        transaction.commit().await.map_err(backend_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::U256;
+    use serde_json::json;
+
+    async fn store_with(rows: Vec<(&str, Value)>) -> SqliteStore {
+        let store = SqliteStore::connect("sqlite::memory:").unwrap();
+        store.migrate().await.unwrap();
+
+        let changes = rows
+            .into_iter()
+            .map(|(id, data)| Change::Save {
+                entity: "Deposit".to_string(),
+                id: id.to_string(),
+                data,
+                metadata: Metadata::default(),
+            })
+            .collect();
+
+        store.commit("source", 1, changes).await.unwrap();
+        store
+    }
+
+    fn query(filters: Vec<Filter>, order_by: OrderBy, order_direction: OrderDirection) -> Query {
+        Query {
+            entity: "Deposit".to_string(),
+            filters,
+            order_by,
+            order_direction,
+            first: 10,
+            skip: 0,
+        }
+    }
+
+    fn ids(rows: Vec<Row>) -> Vec<String> {
+        rows.into_iter().map(|row| row.id).collect()
+    }
+
+    #[tokio::test]
+    async fn orders_uint256_by_value() {
+        let store = store_with(vec![
+            ("a", json!({ "assets": U256::from(16) })),
+            ("b", json!({ "assets": U256::from(9) })),
+            ("c", json!({ "assets": U256::from(255) })),
+        ])
+        .await;
+
+        let asc = store
+            .query(&query(vec![], OrderBy::Uint256("assets".to_string()), OrderDirection::Asc))
+            .await
+            .unwrap();
+        assert_eq!(ids(asc), ["b", "a", "c"]);
+
+        let desc = store
+            .query(&query(vec![], OrderBy::Uint256("assets".to_string()), OrderDirection::Desc))
+            .await
+            .unwrap();
+        assert_eq!(ids(desc), ["c", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn filters_by_field() {
+        let store = store_with(vec![
+            ("a", json!({ "name": "Alice", "user": "0xABCDEF", "count": 1 })),
+            ("b", json!({ "name": "alice", "user": "0xabcdef", "count": 2 })),
+        ])
+        .await;
+
+        let exact = store
+            .query(&query(
+                vec![Filter::Field("name".to_string(), json!("alice"))],
+                OrderBy::Id,
+                OrderDirection::Asc,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(ids(exact), ["b"]);
+
+        let number = store
+            .query(&query(
+                vec![Filter::Field("count".to_string(), json!(1))],
+                OrderBy::Id,
+                OrderDirection::Asc,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(ids(number), ["a"]);
+
+        let ignore_case = store
+            .query(&query(
+                vec![Filter::FieldIgnoreCase("user".to_string(), json!("0xAbCdEf"))],
+                OrderBy::Id,
+                OrderDirection::Asc,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(ids(ignore_case), ["a", "b"]);
+    }
+}
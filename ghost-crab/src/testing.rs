@@ -0,0 +1,353 @@
+//! Helpers to unit test handlers without a node.
+//!
+//! ```ignore
+//! let mut harness = TestHarness::new();
+//!
+//! harness.transport.push_response("eth_getBlockByNumber", json!({ ... }));
+//!
+//! let log = testing::log(VAULTS_REGISTRY, &VaultAdded { vault, ... }, 100);
+//! VaultsRegistry::new().handle(harness.event_context(log, VAULTS_REGISTRY)).await?;
+//!
+//! assert_eq!(harness.started_templates()[0].address, vault);
+//! assert!(harness.load::<Vault>("Vault", &vault.to_string()).await?.is_some());
+//! ```
+use crate::block_handler::BlockContext;
+use crate::event_handler::{BatchEventContext, EventContext};
+use crate::indexer::rpc_manager::Provider;
+use crate::indexer::templates::{Template, TemplateManager};
+use crate::store::{Metadata, Store, StoreError, StoreTransaction};
+use crate::transaction_handler::TransactionContext;
+use alloy::primitives::{Address, LogData, TxHash};
+use alloy::providers::ProviderBuilder;
+use alloy::rpc::client::RpcClient;
+use alloy::rpc::json_rpc::{
+    RequestPacket, Response, ResponsePacket, ResponsePayload, SerializedRequest,
+};
+use alloy::rpc::types::eth::{Log, Transaction, TransactionReceipt};
+use alloy::sol_types::SolEvent;
+use alloy::transports::{TransportError, TransportErrorKind, TransportFut};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{self, Receiver};
+use tower::Service;
+
+#[derive(Default)]
+struct MockState {
+    responses: HashMap<String, VecDeque<Value>>,
+    requests: Vec<(String, Value)>,
+}
+
+/// JSON-RPC transport answering with scripted responses, in the order they were pushed.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Queues the result returned to the next request of the method.
+    pub fn push_response(&self, method: &str, result: Value) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry(method.to_string())
+            .or_default()
+            .push_back(result);
+    }
+
+    /// Returns the method and params of every request received.
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn provider(&self) -> Provider {
+        let client = RpcClient::new(self.clone(), true).boxed();
+        ProviderBuilder::new().on_client(client)
+    }
+
+    fn respond(&self, request: &SerializedRequest) -> Result<Response, TransportError> {
+        let mut state = self.state.lock().unwrap();
+        let method = request.method().to_string();
+
+        let params = match request.params() {
+            Some(params) => serde_json::from_str(params.get()).map_err(TransportError::ser_err)?,
+            None => Value::Null,
+        };
+
+        state.requests.push((method.clone(), params));
+
+        let result = state
+            .responses
+            .get_mut(&method)
+            .and_then(|responses| responses.pop_front())
+            .ok_or_else(|| {
+            TransportErrorKind::custom_str(&format!("No scripted response for {}", method))
+        })?;
+
+        let result = RawValue::from_string(result.to_string()).map_err(TransportError::ser_err)?;
+
+        Ok(Response { id: request.id().clone(), payload: ResponsePayload::Success(result) })
+    }
+}
+
+impl Service<RequestPacket> for MockTransport {
+    type Response = ResponsePacket;
+    type Error = TransportError;
+    type Future = TransportFut<'static>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: RequestPacket) -> Self::Future {
+        let response = match &request {
+            RequestPacket::Single(single) => self.respond(single).map(ResponsePacket::Single),
+            RequestPacket::Batch(batch) => batch
+                .iter()
+                .map(|single| self.respond(single))
+                .collect::<Result<Vec<_>, _>>()
+                .map(ResponsePacket::Batch),
+        };
+
+        Box::pin(async move { response })
+    }
+}
+
+/// Builds a log as emitted by the contract, to use as a fixture.
+pub fn log<E: SolEvent>(address: Address, event: &E, block_number: u64) -> Log {
+    let topics = event.encode_topics().into_iter().map(|topic| topic.0).collect();
+    let data = LogData::new_unchecked(topics, event.encode_data().into());
+
+    Log {
+        inner: alloy::primitives::Log { address, data },
+        block_number: Some(block_number),
+        log_index: Some(0),
+        transaction_hash: Some(TxHash::ZERO),
+        ..Default::default()
+    }
+}
+
+/// Builds the contexts passed to the handlers, backed by a [`MockTransport`].
+///
+/// The contexts share a single store transaction, so the entities saved by a
+/// handler can be read back with [`TestHarness::load`].
+pub struct TestHarness {
+    pub transport: MockTransport,
+    pub store: Store,
+    transaction: StoreTransaction,
+    templates: TemplateManager,
+    rx: Receiver<Template>,
+}
+
+impl Default for TestHarness {
+    fn default() -> Self {
+        TestHarness::new()
+    }
+}
+
+impl TestHarness {
+    pub fn new() -> TestHarness {
+        let (tx, rx) = mpsc::channel::<Template>(100);
+        let store = Store::default();
+
+        TestHarness {
+            transport: MockTransport::new(),
+            transaction: store.transaction(),
+            store,
+            templates: TemplateManager::new(tx),
+            rx,
+        }
+    }
+
+    pub fn with_store(mut self, store: Store) -> TestHarness {
+        self.transaction = store.transaction();
+        self.templates = self.templates.with_store(self.transaction.clone());
+        self.store = store;
+        self
+    }
+
+    /// Loads an entity, including the writes of the handlers not committed yet.
+    pub async fn load<T: DeserializeOwned>(
+        &self,
+        entity: &str,
+        id: &str,
+    ) -> Result<Option<T>, StoreError> {
+        self.transaction.load(entity, id).await
+    }
+
+    /// Commits the writes of the handlers to the store, e.g. to query them.
+    pub async fn commit(&self, source: &str, block_number: u64) -> Result<(), StoreError> {
+        self.transaction.commit(source, block_number).await
+    }
+
+    pub fn event_context(&self, log: Log, contract_address: Address) -> EventContext {
+        let metadata = Metadata {
+            block_number: log.block_number.unwrap_or_default(),
+            log_index: log.log_index,
+            tx_hash: log.transaction_hash,
+        };
+
+        EventContext {
+            log,
+            provider: self.transport.provider(),
+            templates: self.templates.clone(),
+            store: self.transaction.with_metadata(metadata),
+            contract_address,
+        }
+    }
+
+    pub fn batch_event_context(
+        &self,
+        logs: Vec<Log>,
+        from_block: u64,
+        to_block: u64,
+        contract_address: Address,
+    ) -> BatchEventContext {
+        let metadata = Metadata { block_number: to_block, ..Default::default() };
+
+        BatchEventContext {
+            logs,
+            from_block,
+            to_block,
+            provider: self.transport.provider(),
+            templates: self.templates.clone(),
+            store: self.transaction.with_metadata(metadata),
+            contract_address,
+        }
+    }
+
+    pub fn block_context(&self, block_number: u64) -> BlockContext {
+        let metadata = Metadata { block_number, ..Default::default() };
+
+        BlockContext {
+            provider: self.transport.provider(),
+            templates: self.templates.clone(),
+            store: self.transaction.with_metadata(metadata),
+            block_number,
+        }
+    }
+
+    pub fn transaction_context(
+        &self,
+        transaction: Transaction,
+        receipt: TransactionReceipt,
+        contract_address: Address,
+    ) -> TransactionContext {
+        let metadata = Metadata {
+            block_number: transaction.block_number.unwrap_or_default(),
+            log_index: None,
+            tx_hash: Some(transaction.hash),
+        };
+
+        TransactionContext {
+            transaction,
+            receipt,
+            provider: self.transport.provider(),
+            templates: self.templates.clone(),
+            store: self.transaction.with_metadata(metadata),
+            contract_address,
+        }
+    }
+
+    /// Returns the templates started by the handlers since the last call.
+    pub fn started_templates(&mut self) -> Vec<Template> {
+        let mut templates = Vec::new();
+
+        while let Ok(template) = self.rx.try_recv() {
+            templates.push(template);
+        }
+
+        templates
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::event_handler::{EventHandler, HandlerResult};
+    use crate::store::{OrderBy, OrderDirection, Query, StoreBackendInstance};
+    use alloy::primitives::{address, U256};
+    use alloy::sol;
+    use async_trait::async_trait;
+    use serde::{Deserialize, Serialize};
+
+    sol! {
+        event Deposit(address indexed user, uint256 assets);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Balance {
+        assets: U256,
+    }
+
+    /// Adds the assets of each deposit to the balance of the user.
+    struct DepositHandler;
+
+    #[async_trait]
+    impl EventHandler for DepositHandler {
+        async fn handle(&self, ctx: EventContext) -> HandlerResult {
+            let event = Deposit::decode_log_data(ctx.log.data(), true)?;
+            let id = event.user.to_string();
+
+            let balance = ctx.store.load::<Balance>("Balance", &id).await?;
+            let assets = balance.map(|balance| balance.assets).unwrap_or_default();
+
+            ctx.store.save("Balance", &id, &Balance { assets: assets + event.assets })?;
+            Ok(())
+        }
+
+        fn name(&self) -> String {
+            "Vault".to_string()
+        }
+
+        fn event_signature(&self) -> String {
+            Deposit::SIGNATURE.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_back_the_saved_entities() {
+        let vault = address!("0000000000000000000000000000000000000001");
+        let user = address!("0000000000000000000000000000000000000002");
+
+        let backend: StoreBackendInstance =
+            Arc::new(crate::store::sqlite::SqliteStore::connect("sqlite::memory:").unwrap());
+        backend.migrate().await.unwrap();
+        let harness = TestHarness::new().with_store(Store::new(backend));
+
+        for (assets, block_number) in [(10, 100), (5, 101)] {
+            let log = log(vault, &Deposit { user, assets: U256::from(assets) }, block_number);
+            DepositHandler.handle(harness.event_context(log, vault)).await.unwrap();
+        }
+
+        let balance = harness.load::<Balance>("Balance", &user.to_string()).await.unwrap();
+        assert_eq!(balance, Some(Balance { assets: U256::from(15) }));
+
+        harness.commit("Vault", 101).await.unwrap();
+
+        let rows = harness
+            .store
+            .query(&Query {
+                entity: "Balance".to_string(),
+                filters: Vec::new(),
+                order_by: OrderBy::Id,
+                order_direction: OrderDirection::Asc,
+                first: 10,
+                skip: 0,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].metadata.block_number, 101);
+        assert_eq!(harness.store.checkpoint("Vault").await.unwrap(), Some(101));
+    }
+}
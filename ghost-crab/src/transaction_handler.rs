@@ -0,0 +1,393 @@
+use crate::event_handler::HandlerResult;
+use crate::indexer::rpc_manager::Provider;
+use crate::indexer::templates::TemplateManager;
+use crate::latest_block_manager::LatestBlockManager;
+use crate::multicall::Multicall;
+use crate::pending_ranges::PendingRanges;
+use crate::store::{Metadata, Store, StoreTransaction};
+use alloy::eips::{BlockId, BlockNumberOrTag};
+use alloy::primitives::{Address, TxHash};
+use alloy::providers::ext::TraceApi;
+use alloy::providers::Provider as AlloyProvider;
+use alloy::rpc::types::eth::{Block, Transaction, TransactionReceipt};
+use alloy::rpc::types::trace::filter::TraceFilter;
+use alloy::rpc::types::trace::parity::Action;
+use alloy::transports::TransportError;
+use async_trait::async_trait;
+use ghost_crab_common::config::{ConcurrencyConfig, ExecutionMode, TransactionDirection};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub struct TransactionContext {
+    pub transaction: Transaction,
+    pub receipt: TransactionReceipt,
+    pub provider: Provider,
+    pub templates: TemplateManager,
+    pub store: StoreTransaction,
+    pub contract_address: Address,
+}
+
+impl TransactionContext {
+    pub async fn block(&self, hydrate: bool) -> Result<Option<Block>, TransportError> {
+        match self.transaction.block_number {
+            Some(block_number) => {
+                self.provider
+                    .get_block_by_number(BlockNumberOrTag::Number(block_number), hydrate)
+                    .await
+            }
+            None => Err(TransportError::local_usage_str("Error occurred while fetching the current block number within a TransactionHandler. The transaction.block_number value is None.")),
+        }
+    }
+
+    /// Returns a helper that aggregates the `eth_call`s made concurrently at
+    /// the block of the transaction into a single Multicall3 call.
+    pub fn multicall(&self) -> Multicall {
+        let block = self.transaction.block_number.map(BlockId::number).unwrap_or(BlockId::latest());
+        Multicall::new(self.provider.clone(), block)
+    }
+}
+
+pub type TransactionHandlerInstance = Arc<Box<dyn TransactionHandler + Send + Sync>>;
+
+#[async_trait]
+pub trait TransactionHandler {
+    async fn handle(&self, params: TransactionContext) -> HandlerResult;
+    fn name(&self) -> String;
+    fn function_selector(&self) -> Option<[u8; 4]>;
+}
+
+#[derive(Clone)]
+pub struct ProcessTransactionsInput {
+    pub start_block: u64,
+    pub address: Address,
+    pub direction: TransactionDirection,
+    pub step: u64,
+    pub handler: TransactionHandlerInstance,
+    pub templates: TemplateManager,
+    pub provider: Provider,
+    pub store: Store,
+    /// Latest block to index up to, instead of the latest finalized block,
+    /// after which the source stops.
+    pub head: Option<u64>,
+    pub execution_mode: ExecutionMode,
+    pub concurrency: ConcurrencyConfig,
+}
+
+/// JSON-RPC error code of the methods the node doesn't provide.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Returns the hashes of the transactions of the range sent to (or from) the
+/// address, only keeping the calls of the function when there is a selector.
+async fn get_transaction_hashes(
+    provider: &Provider,
+    address: Address,
+    direction: TransactionDirection,
+    function_selector: Option<[u8; 4]>,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<TxHash>, TransportError> {
+    let filter = TraceFilter::default().from_block(from_block).to_block(to_block);
+
+    let filter = match direction {
+        TransactionDirection::To => filter.to_address(vec![address]),
+        TransactionDirection::From => filter.from_address(vec![address]),
+    };
+
+    let traces = provider.trace_filter(&filter).await.map_err(|error| {
+        match error.as_error_resp() {
+            Some(payload) if payload.code == METHOD_NOT_FOUND => TransportError::local_usage_str(
+                "The RPC doesn't provide trace_filter, which transaction handlers need to find the transactions. Use a node with the trace namespace, e.g. Erigon, Reth or Nethermind, Geth doesn't provide it",
+            ),
+            _ => error,
+        }
+    })?;
+
+    let mut seen = HashSet::new();
+    let mut hashes = Vec::new();
+
+    for trace in traces {
+        // Only the top level call represents the transaction itself, the rest are internal calls
+        if !trace.trace.trace_address.is_empty() {
+            continue;
+        }
+
+        // The calldata is in the trace, so the other calls are filtered out before fetching them
+        if let Some(selector) = function_selector {
+            match &trace.trace.action {
+                Action::Call(call) if call.input.starts_with(&selector) => {}
+                _ => continue,
+            }
+        }
+
+        if let Some(hash) = trace.transaction_hash {
+            if seen.insert(hash) {
+                hashes.push(hash);
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+async fn get_transaction_context(
+    provider: &Provider,
+    templates: &TemplateManager,
+    store: &StoreTransaction,
+    address: Address,
+    hash: TxHash,
+) -> Result<TransactionContext, TransportError> {
+    let (transaction, receipt) = tokio::try_join!(
+        provider.get_transaction_by_hash(hash),
+        provider.get_transaction_receipt(hash),
+    )?;
+
+    let transaction =
+        transaction.ok_or_else(|| TransportError::local_usage_str("Transaction not found"))?;
+    let receipt =
+        receipt.ok_or_else(|| TransportError::local_usage_str("Transaction receipt not found"))?;
+
+    let store = store.with_metadata(Metadata {
+        block_number: transaction.block_number.unwrap_or_default(),
+        log_index: None,
+        tx_hash: Some(hash),
+    });
+
+    Ok(TransactionContext {
+        transaction,
+        receipt,
+        provider: provider.clone(),
+        templates: templates.clone(),
+        store,
+        contract_address: address,
+    })
+}
+
+pub async fn process_transactions(
+    ProcessTransactionsInput {
+        start_block,
+        address,
+        direction,
+        step,
+        handler,
+        templates,
+        provider,
+        store,
+        head,
+        execution_mode,
+        concurrency,
+    }: ProcessTransactionsInput,
+) -> Result<(), TransportError> {
+    let function_selector = handler.function_selector();
+    // Keyed apart from the block handlers, which are checkpointed by name
+    let checkpoint = format!("{}:tx:{}", handler.name(), address);
+
+    // The checkpoints were keyed by name before
+    let saved_checkpoint = match store.checkpoint(&checkpoint).await {
+        Ok(None) => store.checkpoint(&handler.name()).await,
+        result => result,
+    };
+
+    let mut current_block = match saved_checkpoint {
+        Ok(Some(block_number)) => start_block.max(block_number + 1),
+        Ok(None) => start_block,
+        Err(error) => return Err(TransportError::local_usage(error)),
+    };
+
+    let mut latest_block_manager =
+        LatestBlockManager::new(provider.clone(), Duration::from_secs(10), head);
+    let mut pending_ranges = PendingRanges::new(checkpoint, &concurrency);
+
+    loop {
+        let mut end_block = current_block + step;
+        let latest_block = latest_block_manager.get().await?;
+
+        if end_block > latest_block {
+            end_block = latest_block;
+        }
+
+        if current_block > end_block {
+            pending_ranges.flush().await?;
+
+            // Running from the cache, there are no blocks past the recorded head
+            if head.is_some() {
+                return Ok(());
+            }
+
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let source = handler.name();
+
+        println!("[{}] Processing transactions from {} to {}", source, current_block, end_block);
+
+        let hashes = get_transaction_hashes(
+            &provider,
+            address,
+            direction,
+            function_selector,
+            current_block,
+            end_block,
+        )
+        .await;
+        let hashes = pending_ranges.tolerate_miss(hashes)?.unwrap_or_default();
+
+        let store_transaction = pending_ranges.transaction(&store);
+        let templates = templates.with_store(store_transaction.clone());
+
+        for hash in hashes {
+            match execution_mode {
+                // The transactions are fetched in the tasks, so concurrently
+                ExecutionMode::Parallel => {
+                    let handler = handler.clone();
+                    let provider = provider.clone();
+                    let templates = templates.clone();
+                    let store_transaction = store_transaction.clone();
+
+                    pending_ranges
+                        .spawn(async move {
+                            let ctx = get_transaction_context(
+                                &provider,
+                                &templates,
+                                &store_transaction,
+                                address,
+                                hash,
+                            )
+                            .await
+                            .map_err(Box::new)?;
+
+                            handler.handle(ctx).await
+                        })
+                        .await?;
+                }
+                // Transactions have no key, so they are handled in order
+                ExecutionMode::Serial | ExecutionMode::Keyed => {
+                    let ctx = get_transaction_context(
+                        &provider,
+                        &templates,
+                        &store_transaction,
+                        address,
+                        hash,
+                    )
+                    .await;
+
+                    let Some(ctx) = pending_ranges.tolerate_miss(ctx)? else {
+                        continue;
+                    };
+
+                    let result = handler.handle(ctx).await;
+                    pending_ranges
+                        .tolerate_miss(result.map_err(TransportError::LocalUsageError))?;
+                }
+            }
+        }
+
+        pending_ranges.push(store_transaction, end_block).await?;
+
+        current_block = end_block + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockTransport;
+    use alloy::primitives::{address, B256};
+    use serde_json::{json, Value};
+
+    const VAULT: Address = address!("0000000000000000000000000000000000000001");
+    const USER: Address = address!("0000000000000000000000000000000000000002");
+
+    fn trace(
+        hash: TxHash,
+        from: Address,
+        to: Address,
+        input: &str,
+        trace_address: &[u64],
+    ) -> Value {
+        json!({
+            "action": {
+                "callType": "call",
+                "from": from,
+                "gas": "0x0",
+                "input": input,
+                "to": to,
+                "value": "0x0"
+            },
+            "blockHash": B256::ZERO,
+            "blockNumber": 5,
+            "result": { "gasUsed": "0x0", "output": "0x" },
+            "subtraces": 0,
+            "traceAddress": trace_address,
+            "transactionHash": hash,
+            "transactionPosition": 0,
+            "type": "call"
+        })
+    }
+
+    async fn hashes(
+        traces: Vec<Value>,
+        direction: TransactionDirection,
+        function_selector: Option<[u8; 4]>,
+    ) -> (Vec<TxHash>, Value) {
+        let transport = MockTransport::new();
+        transport.push_response("trace_filter", json!(traces));
+
+        let hashes = get_transaction_hashes(
+            &transport.provider(),
+            VAULT,
+            direction,
+            function_selector,
+            0,
+            10,
+        )
+        .await
+        .unwrap();
+
+        (hashes, transport.requests()[0].1[0].clone())
+    }
+
+    #[tokio::test]
+    async fn only_keeps_the_top_level_calls() {
+        let traces = vec![
+            trace(B256::with_last_byte(1), USER, VAULT, "0x", &[]),
+            // A call made by another transaction to the vault
+            trace(B256::with_last_byte(2), USER, VAULT, "0x", &[0]),
+            // Another call of the same transaction
+            trace(B256::with_last_byte(1), USER, VAULT, "0x", &[]),
+        ];
+
+        let (hashes, filter) = hashes(traces, TransactionDirection::To, None).await;
+
+        assert_eq!(hashes, vec![B256::with_last_byte(1)]);
+        assert_eq!(filter["toAddress"], json!([VAULT]));
+        assert_eq!(filter["fromBlock"], json!("0x0"));
+        assert_eq!(filter["toBlock"], json!("0xa"));
+    }
+
+    #[tokio::test]
+    async fn filters_the_calls_by_selector() {
+        let traces = vec![
+            trace(B256::with_last_byte(1), USER, VAULT, "0x6e553f650000", &[]),
+            trace(B256::with_last_byte(2), USER, VAULT, "0xba087652", &[]),
+            trace(B256::with_last_byte(3), USER, VAULT, "0x", &[]),
+        ];
+
+        let selector = Some([0x6e, 0x55, 0x3f, 0x65]);
+        let (hashes, _) = hashes(traces, TransactionDirection::To, selector).await;
+
+        assert_eq!(hashes, vec![B256::with_last_byte(1)]);
+    }
+
+    #[tokio::test]
+    async fn filters_the_transactions_sent_from_the_address() {
+        let traces = vec![trace(B256::with_last_byte(1), VAULT, USER, "0x", &[])];
+
+        let (hashes, filter) = hashes(traces, TransactionDirection::From, None).await;
+
+        assert_eq!(hashes, vec![B256::with_last_byte(1)]);
+        assert_eq!(filter["fromAddress"], json!([VAULT]));
+        assert_ne!(filter["toAddress"], json!([VAULT]));
+    }
+}
@@ -0,0 +1,99 @@
+use alloy::primitives::U256;
+use ghost_crab::prelude::*;
+use ghost_crab::testing::{self, TestHarness};
+use std::sync::Mutex;
+
+const VAULT: Address = address!("0000000000000000000000000000000000000001");
+
+/// Assets of the deposits handled by the batch handler.
+static DEPOSITS: Mutex<Vec<(u64, u64, Vec<U256>)>> = Mutex::new(Vec::new());
+
+#[batch_event_handler(Vault.Deposited, abi = "tests/fixtures/Vault.json")]
+async fn DepositBatches(ctx: BatchEventContext) {
+    let assets = events.iter().map(|event| event.inner.assets).collect();
+    DEPOSITS.lock().unwrap().push((ctx.from_block, ctx.to_block, assets));
+}
+
+mod unindexed {
+    alloy::sol! {
+        event Deposited(address user, uint256 assets);
+    }
+}
+
+#[tokio::test]
+async fn decodes_the_logs_of_the_batch() {
+    let harness = TestHarness::new();
+    let handler = DepositBatches::new();
+
+    let deposit = |assets: u64, block_number| {
+        let event = DepositBatchesContract::Deposited {
+            user: Address::repeat_byte(2),
+            assets: U256::from(assets),
+        };
+
+        testing::log(VAULT, &event, block_number)
+    };
+
+    // A log matching the signature with other indexed fields is skipped
+    let unindexed = unindexed::Deposited { user: Address::repeat_byte(2), assets: U256::from(3) };
+    let logs = vec![deposit(10, 5), testing::log(VAULT, &unindexed, 6), deposit(5, 7)];
+
+    assert!(handler.batch());
+    handler.handle_batch(harness.batch_event_context(logs, 0, 10, VAULT)).await.unwrap();
+
+    // A single log is handled as a batch of its block
+    handler.handle(harness.event_context(deposit(1, 15), VAULT)).await.unwrap();
+
+    assert_eq!(
+        *DEPOSITS.lock().unwrap(),
+        [(0, 10, vec![U256::from(10), U256::from(5)]), (15, 15, vec![U256::from(1)])]
+    );
+}
+
+entities!(config = "tests/fixtures/config.json");
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn saves_and_updates_the_entities() {
+    use alloy::primitives::{Bytes, B256, I256};
+    use ghost_crab::store::{sqlite::SqliteStore, Store, StoreBackend};
+
+    let backend = SqliteStore::connect("sqlite::memory:").unwrap();
+    backend.migrate().await.unwrap();
+    let harness = TestHarness::new().with_store(Store::new(Arc::new(backend)));
+    let log = testing::log(
+        VAULT,
+        &DepositBatchesContract::Deposited { user: Address::ZERO, assets: U256::ZERO },
+        100,
+    );
+    let store = harness.event_context(log, VAULT).store;
+
+    let mut vault = Vault {
+        id: VAULT.to_string(),
+        name: "Vault".to_string(),
+        owner: Address::repeat_byte(2),
+        paused: true,
+        deposits: 2,
+        delta: -1,
+        assets: U256::MAX,
+        pnl: I256::MINUS_ONE,
+        data: Bytes::from_static(&[1, 2, 3]),
+        salt: B256::repeat_byte(4),
+        ..Default::default()
+    };
+
+    vault.save(&store).unwrap();
+
+    // The metadata is set from the context of the handler
+    assert_eq!(vault.block_number, 100);
+    assert_eq!(vault.tx_hash, Some(Default::default()));
+    assert_eq!(Vault::load(&store, &vault.id).await.unwrap(), Some(vault.clone()));
+
+    let updated = Vault::update(&store, &vault.id, |vault| vault.deposits += 1).await.unwrap();
+    assert_eq!(updated.map(|vault| vault.deposits), Some(3));
+
+    assert_eq!(Vault::update(&store, "missing", |_| {}).await.unwrap(), None);
+
+    Vault::delete(&store, &vault.id).unwrap();
+    assert_eq!(Vault::load(&store, &vault.id).await.unwrap(), None);
+}